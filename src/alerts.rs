@@ -0,0 +1,176 @@
+use std::{collections::HashMap, env, ffi::CString, fs, path::Path, process::Command, sync::Once, thread};
+
+use crate::config::AlertConfig;
+
+const NOTIFICATIONS_NAME: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+// Each logged in user's runtime directory, holding their session bus socket as `bus`
+const USER_RUNTIME_ROOT: &str = "/run/user";
+const SYSLOG_IDENT: &std::ffi::CStr = c"fancontrol";
+
+static OPEN_SYSLOG: Once = Once::new();
+
+// Something worth waking a human for, or the all clear after one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    pub sensor: String,
+    pub message: String,
+    pub resolved: bool,
+}
+
+// Sends alerts to every hook enabled in the config, none of them block the caller.
+pub struct Alerter {
+    config: AlertConfig,
+}
+
+impl Alert {
+    pub fn new(sensor: &str, message: String) -> Self {
+        Self { sensor: sensor.to_string(), message, resolved: false }
+    }
+
+    pub fn resolved(sensor: &str, message: String) -> Self {
+        Self { sensor: sensor.to_string(), message, resolved: true }
+    }
+}
+
+impl Alerter {
+    // With stderr going to journald every alert is already in the journal, syslog would only add it again.
+    pub fn new(config: &AlertConfig) -> Self {
+        let mut config = config.clone();
+        if config.syslog && env::var_os("JOURNAL_STREAM").is_some() {
+            config.syslog = false;
+        }
+        Self { config }
+    }
+
+    pub fn send(&self, alert: &Alert) {
+        if alert.resolved {
+            log::info!("{}: {}", alert.sensor, alert.message);
+        } else {
+            log::warn!("{}: {}", alert.sensor, alert.message);
+        }
+
+        if self.config.syslog {
+            send_syslog(alert);
+        }
+
+        if let Some(command) = &self.config.command {
+            run_command(command, alert);
+        }
+
+        if self.config.desktop {
+            send_desktop_notification(alert);
+        }
+    }
+}
+
+fn send_syslog(alert: &Alert) {
+    let priority = if alert.resolved { libc::LOG_NOTICE } else { libc::LOG_ALERT };
+    let message = match CString::new(format!("{}: {}", alert.sensor, alert.message)) {
+        Ok(m) => m,
+        Err(_) => return,
+    };
+
+    // SAFETY: the ident is a 'static C string, openlog keeps the pointer and it outlives every syslog call
+    OPEN_SYSLOG.call_once(|| unsafe { libc::openlog(SYSLOG_IDENT.as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) });
+    // SAFETY: "%s" takes exactly the one argument passed, a valid C string, so the message can't be read as a format
+    unsafe {
+        libc::syslog(priority, c"%s".as_ptr(), message.as_ptr());
+    }
+}
+
+// Runs through `sh -c` with the alert in FANCONTROL_ALERT, FANCONTROL_SENSOR and FANCONTROL_RESOLVED.
+fn run_command(command: &str, alert: &Alert) {
+    let mut child = Command::new("sh");
+    child.arg("-c").arg(command)
+        .env("FANCONTROL_ALERT", &alert.message)
+        .env("FANCONTROL_SENSOR", &alert.sensor)
+        .env("FANCONTROL_RESOLVED", if alert.resolved { "1" } else { "0" });
+
+    let command = command.to_string();
+    thread::spawn(move || match child.status() {
+        Ok(status) if !status.success() => log::warn!("Alert command '{command}' exited with {status}"),
+        Ok(_) => {}
+        Err(e) => log::warn!("Unable to run alert command '{command}': {e}"),
+    });
+}
+
+fn send_desktop_notification(alert: &Alert) {
+    let summary = if alert.resolved { format!("{} recovered", alert.sensor) } else { format!("{} needs attention", alert.sensor) };
+    let body = alert.message.clone();
+    let icon = if alert.resolved { "dialog-information" } else { "dialog-warning" };
+
+    thread::spawn(move || {
+        let buses = session_buses(Path::new(USER_RUNTIME_ROOT));
+        if buses.is_empty() {
+            log::warn!("Unable to send desktop notification: no session bus in {USER_RUNTIME_ROOT}, is anyone logged in?");
+        }
+
+        for address in buses {
+            let result = zbus::blocking::connection::Builder::address(address.as_str()).and_then(|b| b.build()).and_then(|connection| {
+                let hints: HashMap<&str, zbus::zvariant::Value> = HashMap::new();
+                connection.call_method(
+                    Some(NOTIFICATIONS_NAME),
+                    NOTIFICATIONS_PATH,
+                    Some(NOTIFICATIONS_NAME),
+                    "Notify",
+                    &("fancontrol", 0u32, icon, summary.as_str(), body.as_str(), Vec::<&str>::new(), hints, -1i32),
+                )
+            });
+
+            if let Err(e) = result {
+                log::warn!("Unable to send desktop notification over {address}: {e}");
+            }
+        }
+    });
+}
+
+// The daemon runs outside any login session, so notifications go to the session bus of every logged in user.
+// Started from inside a session, e.g. `run` in a desktop terminal, only that session's bus is used.
+fn session_buses(runtime_root: &Path) -> Vec<String> {
+    if let Ok(address) = env::var("DBUS_SESSION_BUS_ADDRESS") {
+        return vec![address];
+    }
+
+    let entries = match fs::read_dir(runtime_root) {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut buses: Vec<String> = entries.flatten()
+        .filter(|e| e.file_name().to_str().is_some_and(|n| n.bytes().all(|b| b.is_ascii_digit())))
+        .map(|e| e.path().join("bus"))
+        .filter(|bus| bus.exists())
+        .map(|bus| format!("unix:path={}", bus.display()))
+        .collect();
+    buses.sort();
+    return buses;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::{Duration, Instant}};
+
+    use super::*;
+
+    #[test]
+    fn command_hook_receives_the_alert_in_its_environment() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("alert");
+        let config = AlertConfig {
+            command: Some(format!("printf '%s|%s|%s' \"$FANCONTROL_SENSOR\" \"$FANCONTROL_ALERT\" \"$FANCONTROL_RESOLVED\" > {}", output.display())),
+            syslog: false,
+            desktop: false,
+        };
+
+        Alerter::new(&config).send(&Alert::new("nct6775/fan2", "fan stopped".into()));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !output.exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(fs::read_to_string(&output).unwrap(), "nct6775/fan2|fan stopped|0");
+    }
+}
@@ -0,0 +1,137 @@
+use std::{net::SocketAddr, path::PathBuf, str::FromStr};
+
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
+
+use fancontrol::units::Duty;
+
+const AFTER_HELP: &str = "CHIP is the chip ID printed by `list`, which stays the same across reboots. The hwmonN name or a chip name that only one chip has also work.
+
+Reading never needs root. Commands that change fan speeds do, except `set --for` and `profile`, which go through the running daemon's socket.
+
+Exit codes: 0 success, 1 error, 2 invalid usage, 3 chip or sensor not found";
+
+#[derive(Parser)]
+#[command(name = "fancontrol", version, about = "Pair fans with PWM outputs and control them from temperature curves", after_help = AFTER_HELP)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Config file holding pairings and curves
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Directory containing the hwmonN chips [env: FANCONTROL_SYSFS_ROOT]
+    #[arg(long, global = true, value_name = "PATH")]
+    pub sysfs_root: Option<PathBuf>,
+
+    /// Print temperatures in °F instead of °C
+    #[arg(long, global = true)]
+    pub fahrenheit: bool,
+
+    /// Run against a simulated chip instead of real hardware, in builds with the simulator feature
+    #[arg(long, global = true)]
+    pub simulate: bool,
+
+    /// Same as `run`, kept for existing service files
+    #[arg(long, hide = true)]
+    pub daemon: bool,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// List every hwmon chip with its fans, temps and pwms
+    List {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Print a single reading, e.g. `get nct6775/fan1`
+    Get {
+        #[arg(value_name = "CHIP/SENSOR")]
+        target: SensorRef,
+    },
+    /// Set a PWM duty, e.g. `set nct6775/pwm2 128` or `set nct6775/pwm2 60%`
+    Set {
+        #[arg(value_name = "CHIP/PWM")]
+        target: SensorRef,
+        #[arg(value_name = "VALUE|PERCENT")]
+        value: Duty,
+        /// Ask the running daemon to hold the duty for this many minutes instead, no root needed
+        #[arg(long = "for", value_name = "MINUTES")]
+        minutes: Option<u64>,
+    },
+    /// Pair the fans of a chip with its PWM outputs and save the result
+    #[command(group(ArgGroup::new("mode").required(true).args(["auto", "manual"])))]
+    Pair {
+        #[arg(long)]
+        auto: bool,
+        #[arg(long)]
+        manual: bool,
+        chip: String,
+    },
+    /// Measure the duty to RPM response of every paired fan on a chip and save it with the pairings
+    Calibrate {
+        chip: String,
+    },
+    /// Run the control loop from the saved pairings and curves without any prompts
+    Run {
+        /// Serve Prometheus metrics on this address, e.g. 127.0.0.1:9782
+        #[arg(long, value_name = "ADDR")]
+        metrics_listen: Option<SocketAddr>,
+        /// Run this profile ahead of the rules until it is cleared over the control socket, e.g. with `profile --auto`
+        #[arg(long, value_name = "NAME")]
+        profile: Option<String>,
+    },
+    /// Show the running daemon's profiles, or switch it to one, no root needed
+    Profile {
+        /// Profile to switch to, e.g. silent
+        #[arg(conflicts_with = "auto")]
+        name: Option<String>,
+        /// Switch back after this many minutes
+        #[arg(long = "for", value_name = "MINUTES", requires = "name")]
+        minutes: Option<u64>,
+        /// Let the rules pick the profile again
+        #[arg(long)]
+        auto: bool,
+    },
+    /// Serve Prometheus metrics of the readings without controlling anything, no root needed
+    Metrics {
+        #[arg(long, value_name = "ADDR")]
+        listen: SocketAddr,
+    },
+    /// Full screen view of every chip with live readings, manual duty control, pairing and curve editing
+    #[command(alias = "monitor")]
+    Dashboard,
+    /// Convert an lm-sensors fancontrol file into pairings and curves and save them
+    ImportFancontrol {
+        #[arg(default_value = fancontrol::lm_sensors::DEFAULT_PATH)]
+        path: PathBuf,
+    },
+    /// Write the pairings and curves as an lm-sensors fancontrol file, to stdout without a path
+    ExportFancontrol {
+        path: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Yaml,
+}
+
+#[derive(Clone, Debug)]
+pub struct SensorRef {
+    pub chip: String,
+    pub sensor: String,
+}
+
+impl FromStr for SensorRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once('/') {
+            Some((chip, sensor)) if !chip.is_empty() && !sensor.is_empty() => Ok(Self { chip: chip.to_string(), sensor: sensor.to_string() }),
+            _ => Err(format!("expected CHIP/SENSOR, got '{s}'")),
+        }
+    }
+}
@@ -0,0 +1,427 @@
+use std::{net::SocketAddr, path::{Path, PathBuf}, sync::Arc, thread};
+
+use fancontrol::{config::Config, control_socket::{Client, Method}, error::Error, fan_service::FanService, lm_sensors::FancontrolFile, metrics::Metrics, hwmon::{hwmon::Hwmon, pwm_state::{self, PwmStateGuard}}, hwmon_service::HwmonService, snapshot, units::{Duty, Temperature}};
+
+use crate::{cli::{Command, OutputFormat, SensorRef}, daemon, dashboard, program};
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_NOT_FOUND: i32 = 3;
+
+pub struct Context {
+    pub config_path: PathBuf,
+    pub sysfs_root: PathBuf,
+    pub fahrenheit: bool,
+}
+
+pub fn dispatch(command: Command, context: &Context) -> i32 {
+    match command {
+        Command::List { format } => list(context, format),
+        Command::Get { target } => get(context, &target),
+        Command::Set { target, value, minutes: None } => set(context, &target, value),
+        Command::Set { target, value, minutes: Some(minutes) } => set_through_daemon(context, &target, value, minutes),
+        Command::Pair { auto: true, manual: false, chip } => pair(context, &chip, true),
+        Command::Pair { auto: false, manual: true, chip } => pair(context, &chip, false),
+        Command::Pair { .. } => {
+            eprintln!("Pass one of --auto or --manual");
+            EXIT_ERROR
+        }
+        Command::Calibrate { chip } => calibrate(context, &chip),
+        Command::Run { metrics_listen, profile } => daemon::run(&context.config_path, &context.sysfs_root, metrics_listen, profile.as_deref()),
+        Command::Profile { name, minutes, auto } => profile(context, name, minutes, auto),
+        Command::Metrics { listen } => serve_metrics(context, listen),
+        Command::Dashboard => dashboard::run(context),
+        Command::ImportFancontrol { path } => import_fancontrol(context, &path),
+        Command::ExportFancontrol { path } => export_fancontrol(context, path.as_deref()),
+    }
+}
+
+fn list(context: &Context, format: OutputFormat) -> i32 {
+    let service = match load_service(context) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+    if service.hwmons.is_empty() {
+        eprintln!("No hwmon chips found in {}", context.sysfs_root.display());
+        return EXIT_NOT_FOUND;
+    }
+
+    if format != OutputFormat::Text {
+        return print_snapshot(&service.hwmons, format);
+    }
+
+    for hwmon in service.hwmons.iter() {
+        println!("{} [{}] ({} fans, {} temp sensors, {} pwm inputs)", hwmon.id, hwmon.dir_name(), hwmon.fans.len(), hwmon.temps.len(), hwmon.pwms.len());
+
+        for fan in sorted(&hwmon.fans, |f| f.index) {
+            let confidence = fan.pair_confidence.map(|c| format!(" ({:.0}% confidence)", c * 100.0)).unwrap_or_default();
+            let pairing = fan.paired_pwm.as_ref().map(|p| format!(" -> {}{confidence}", p.name)).unwrap_or_default();
+            println!("  fan{} {}: {}{pairing}", fan.index, fan.label, fan.get_formatted_speed());
+        }
+
+        for temp in sorted(&hwmon.temps, |t| t.index.parse::<u32>().unwrap_or(0)) {
+            let reading = match temp.get_temp() {
+                Ok(t) if context.fahrenheit => format!("{t:#}"),
+                Ok(t) => t.to_string(),
+                Err(e) => e.reason().to_string(),
+            };
+            println!("  temp{} {}: {reading}", temp.index, temp.label);
+        }
+
+        for pwm in sorted(&hwmon.pwms, |p| p.index.parse::<u32>().unwrap_or(0)) {
+            let mode = pwm.read_enable().map(|m| format!(" (mode {m})")).unwrap_or_default();
+            let duty = pwm.read_duty().map(|d| d.to_string()).unwrap_or_else(|e| e.reason().to_string());
+            println!("  {}: {duty}{mode}", pwm.name);
+        }
+    }
+
+    return EXIT_OK;
+}
+
+fn print_snapshot(hwmons: &[Hwmon], format: OutputFormat) -> i32 {
+    let chips = snapshot::snapshot(hwmons);
+    let output = match format {
+        OutputFormat::Yaml => serde_yaml::to_string(&chips).map_err(|e| e.to_string()),
+        _ => serde_json::to_string_pretty(&chips).map_err(|e| e.to_string()),
+    };
+
+    match output {
+        Ok(s) => println!("{}", s.trim_end()),
+        Err(e) => {
+            eprintln!("Unable to serialize readings: {e}");
+            return EXIT_ERROR;
+        }
+    }
+
+    return EXIT_OK;
+}
+
+fn get(context: &Context, target: &SensorRef) -> i32 {
+    let hwmon = match load_chip(context, &target.chip) {
+        Ok(h) => h,
+        Err(code) => return code,
+    };
+
+    // fanN and tempN read their _input file, anything else is read as is
+    let is_input = !target.sensor.contains('_');
+    let is_temp = is_input && target.sensor.starts_with("temp");
+    let attribute = if is_temp || (is_input && target.sensor.starts_with("fan")) {
+        format!("{}_input", target.sensor)
+    } else {
+        target.sensor.clone()
+    };
+
+    match hwmon.read_raw(&attribute) {
+        Ok(raw) if is_temp => match raw.parse::<i32>().map(Temperature::from_millicelsius) {
+            Ok(temp) if context.fahrenheit => println!("{}", (temp.fahrenheit() * 10.0).round() / 10.0),
+            Ok(temp) => println!("{}", temp.celsius()),
+            Err(_) => {
+                eprintln!("{}", Error::Parse { path: hwmon.path().join(&attribute), raw });
+                return EXIT_ERROR;
+            }
+        },
+        Ok(raw) => println!("{raw}"),
+        Err(Error::NotFound { .. }) => {
+            eprintln!("{} has no sensor {}", target.chip, target.sensor);
+            return EXIT_NOT_FOUND;
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            return EXIT_ERROR;
+        }
+    }
+
+    return EXIT_OK;
+}
+
+fn set(context: &Context, target: &SensorRef, value: Duty) -> i32 {
+    let hwmon = match load_chip(context, &target.chip) {
+        Ok(h) => h,
+        Err(code) => return code,
+    };
+
+    let pwm = match hwmon.pwms.iter().find(|p| p.name == target.sensor) {
+        Some(p) => p,
+        None => {
+            eprintln!("{} has no pwm output {}", target.chip, target.sensor);
+            return EXIT_NOT_FOUND;
+        }
+    };
+
+    if let Err(e) = pwm.set_duty(value) {
+        eprintln!("{e}");
+        if matches!(e, Error::PermissionDenied { .. }) {
+            eprintln!("Run it with sudo, or use --for MINUTES to have the running daemon hold the duty");
+        }
+        return EXIT_ERROR;
+    }
+
+    return EXIT_OK;
+}
+
+// Overrides the curve through the daemon's socket, the daemon puts the curve back once the time is up.
+fn set_through_daemon(context: &Context, target: &SensorRef, value: Duty, minutes: u64) -> i32 {
+    let mut client = match connect_to_daemon(context) {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+
+    let pwm = format!("{}/{}", target.chip, target.sensor);
+    match client.call(Method::Override { pwm: Some(pwm.clone()), duty: value, minutes }) {
+        Ok(_) => println!("The daemon holds {pwm} at {value} for {minutes} min"),
+        Err(e) => {
+            eprintln!("The daemon refused: {e}");
+            return EXIT_ERROR;
+        }
+    }
+
+    return EXIT_OK;
+}
+
+fn profile(context: &Context, name: Option<String>, minutes: Option<u64>, auto: bool) -> i32 {
+    let mut client = match connect_to_daemon(context) {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+
+    let result = match (name, auto) {
+        (None, false) => client.call(Method::GetProfile).map(|status| {
+            let remaining = status["selected_for_secs"].as_u64().map(|s| format!(", {} min left", s.div_ceil(60))).unwrap_or_default();
+            println!("{} ({}{remaining})", status["active"].as_str().unwrap_or_default(), status["reason"].as_str().unwrap_or_default());
+            let names: Vec<&str> = status["profiles"].as_array().into_iter().flatten().filter_map(|n| n.as_str()).collect();
+            println!("Profiles: {}", names.join(", "));
+        }),
+        (name, _) => client.call(Method::SetProfile { name: name.clone(), minutes }).map(|_| match (name, minutes) {
+            (Some(name), Some(minutes)) => println!("Running {name} for {minutes} min"),
+            (Some(name), None) => println!("Running {name} until changed"),
+            (None, _) => println!("The rules pick the profile again"),
+        }),
+    };
+
+    if let Err(e) = result {
+        eprintln!("The daemon refused: {e}");
+        return EXIT_ERROR;
+    }
+
+    return EXIT_OK;
+}
+
+// Prints why the daemon can't be reached and returns the exit code for it.
+fn connect_to_daemon(context: &Context) -> Result<Client, i32> {
+    let socket_path = Config::load_or_default(&context.config_path).socket.path;
+    match Client::connect(&socket_path) {
+        Ok(c) => Ok(c),
+        Err(e) => {
+            eprintln!("Unable to reach the daemon on {}: {e}", socket_path.display());
+            Err(EXIT_ERROR)
+        }
+    }
+}
+
+// Only reads, so unlike `run --metrics-listen` it works without root. No loops run, the health counters stay at 0.
+fn serve_metrics(context: &Context, listen: SocketAddr) -> i32 {
+    let service = match load_service(context) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+
+    let metrics = Arc::new(Metrics::new(Arc::new(service.hwmons)));
+    match metrics.serve(listen) {
+        Ok(addr) => println!("Serving metrics on http://{addr}/metrics"),
+        Err(e) => {
+            eprintln!("Unable to serve metrics on {listen}: {e}");
+            return EXIT_ERROR;
+        }
+    }
+
+    loop {
+        thread::park();
+    }
+}
+
+fn pair(context: &Context, chip: &str, auto: bool) -> i32 {
+    let mut config = match load_config_for_update(&context.config_path) {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+    let mut service = match load_service(context) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+
+    let hwmon = match service.find(chip) {
+        Some(i) => &mut service.hwmons[i],
+        None => {
+            eprintln!("No chip named {chip}");
+            return EXIT_NOT_FOUND;
+        }
+    };
+
+    let _pwm_state_guard = PwmStateGuard::new();
+    pwm_state::restore_on_signal();
+
+    if auto {
+        program::auto_pair_unattended(hwmon);
+    } else {
+        program::pair_fans(hwmon, false);
+        program::print_pairings(hwmon);
+    }
+
+    if !hwmon.has_pairings() {
+        eprintln!("No fans were paired on {chip}");
+        return EXIT_ERROR;
+    }
+
+    config.set_pairings_for(hwmon);
+    if let Err(e) = config.save(&context.config_path) {
+        eprintln!("Error saving config to {}: {e}", context.config_path.display());
+        return EXIT_ERROR;
+    }
+
+    return EXIT_OK;
+}
+
+fn calibrate(context: &Context, chip: &str) -> i32 {
+    let mut config = match load_config_for_update(&context.config_path) {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+    let mut service = match load_service(context) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+
+    let hwmon = match service.find(chip) {
+        Some(i) => &mut service.hwmons[i],
+        None => {
+            eprintln!("No chip named {chip}");
+            return EXIT_NOT_FOUND;
+        }
+    };
+
+    if !hwmon.has_pairings() {
+        eprintln!("No fans are paired on {chip}, run `pair` first");
+        return EXIT_ERROR;
+    }
+
+    let _pwm_state_guard = PwmStateGuard::new();
+    pwm_state::restore_on_signal();
+
+    if program::calibrate_fans(hwmon) == 0 {
+        eprintln!("No fans were calibrated on {chip}");
+        return EXIT_ERROR;
+    }
+
+    config.set_pairings_for(hwmon);
+    if let Err(e) = config.save(&context.config_path) {
+        eprintln!("Error saving config to {}: {e}", context.config_path.display());
+        return EXIT_ERROR;
+    }
+
+    return EXIT_OK;
+}
+
+fn import_fancontrol(context: &Context, path: &Path) -> i32 {
+    let file = match std::fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|s| FancontrolFile::parse(&s)) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Unable to read {}: {e}", path.display());
+            return EXIT_ERROR;
+        }
+    };
+
+    let hwmon_service = match load_service(context) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+    let config = match load_config_for_update(&context.config_path) {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+    let mut service = FanService::new(hwmon_service, config);
+
+    let imported = file.import(&mut service);
+    if imported == 0 {
+        eprintln!("None of the pwms in {} exist on this machine", path.display());
+        return EXIT_NOT_FOUND;
+    }
+
+    if let Err(e) = service.config.save(&context.config_path) {
+        eprintln!("Error saving config to {}: {e}", context.config_path.display());
+        return EXIT_ERROR;
+    }
+
+    println!("Imported {imported} of {} pwm channels into {}", file.channels.len(), context.config_path.display());
+    return EXIT_OK;
+}
+
+fn export_fancontrol(context: &Context, path: Option<&Path>) -> i32 {
+    let hwmon_service = match load_service(context) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+    let service = FanService::new(hwmon_service, Config::load_or_default(&context.config_path));
+
+    let contents = FancontrolFile::export(&service).to_string();
+    match path {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, contents) {
+                eprintln!("Unable to write {}: {e}", path.display());
+                return EXIT_ERROR;
+            }
+        }
+        None => print!("{contents}"),
+    }
+
+    return EXIT_OK;
+}
+
+// For commands that save the config, prints why it can't be read instead of starting over from the defaults.
+pub fn load_config_for_update(path: &Path) -> Result<Config, i32> {
+    return Config::load_for_update(path).map_err(|e| {
+        eprintln!("Unable to read {}, fix or remove it first: {e}", path.display());
+        EXIT_ERROR
+    });
+}
+
+// Prints why discovery failed and returns the exit code for it.
+pub fn load_service(context: &Context) -> Result<HwmonService, i32> {
+    let config = Config::load_or_default(&context.config_path);
+
+    let mut service = match HwmonService::new(context.sysfs_root.clone()) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Unable to discover hwmon chips: {e}");
+            return Err(exit_code(&e));
+        }
+    };
+    service.initialize_hwmons();
+    service.load_pairings(&config);
+
+    return Ok(service);
+}
+
+pub fn exit_code(error: &Error) -> i32 {
+    match error {
+        Error::NotFound { .. } => EXIT_NOT_FOUND,
+        _ => EXIT_ERROR,
+    }
+}
+
+fn load_chip(context: &Context, chip: &str) -> Result<Hwmon, i32> {
+    let mut service = load_service(context)?;
+
+    match service.find(chip) {
+        Some(i) => Ok(service.hwmons.swap_remove(i)),
+        None => {
+            eprintln!("No chip named {chip}");
+            Err(EXIT_NOT_FOUND)
+        }
+    }
+}
+
+fn sorted<T, K: Ord>(items: &[T], key: impl Fn(&T) -> K) -> Vec<&T> {
+    let mut list: Vec<&T> = items.iter().collect();
+    list.sort_by_key(|item| key(item));
+    return list;
+}
@@ -0,0 +1,469 @@
+use std::{fs, io::{self, Write}, path::{Path, PathBuf}, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{curve::Curve, hwmon::{calibration::FanCalibration, hwmon::Hwmon}, profiles::ProfileRule, temp_source::{SourceConfig, TempRef}, units::Duty};
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/fancontrol-rs.toml";
+pub const DEFAULT_SOCKET_PATH: &str = "/run/fancontrol.sock";
+const DEFAULT_INTERVAL_MS: u64 = 2000;
+const DEFAULT_TRANSITION_SECS: u64 = 10;
+// The name of the top level `curves`
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    #[serde(default)]
+    pub pairings: Vec<Pairing>,
+    #[serde(default)]
+    pub curves: Vec<CurveConfig>,
+    // The profile used when no rule matches and none was picked over the socket, `default` without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    // How long duties take to ease over to the curves of a newly active profile
+    #[serde(default = "default_transition_secs")]
+    pub transition_secs: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<Profile>,
+    // Checked in order on every tick, the first one that matches picks the profile
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<ProfileRule>,
+    #[serde(default)]
+    pub safety: SafetyConfig,
+    #[serde(default)]
+    pub alerts: AlertConfig,
+    #[serde(default)]
+    pub socket: SocketConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SafetyConfig {
+    // In °C, the lower of this and a sensor's own tempN_crit/tempN_max applies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub critical_temp: Option<f32>,
+    // A temperature that doesn't change at all for this long is treated as failed. Off by default, 0,
+    // since an idle sensor on a board that reports whole degrees can sit on one value for a long time
+    #[serde(default)]
+    pub stale_after_secs: u64,
+}
+
+// Which chip a pairing or curve belongs to, by the stable chip ID from `Hwmon::id`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ChipRef {
+    #[serde(default)]
+    pub chip: String,
+    // Written by older versions, only read so their configs keep working until saved again
+    #[serde(default, skip_serializing)]
+    pub hwmon_name: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub device_path: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Pairing {
+    #[serde(flatten)]
+    pub chip: ChipRef,
+    pub fan_index: i32,
+    pub pwm_index: String,
+    // Set by auto pairing, missing for pairings made by hand
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub calibration: Option<FanCalibration>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CurveConfig {
+    #[serde(flatten)]
+    pub chip: ChipRef,
+    // Only drives this pwm, a curve without one drives every other paired pwm on the chip
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pwm_index: Option<String>,
+    // The temps the curve follows, from any chip
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<SourceConfig>,
+    // A single temp by index as written before `source`, kept as is until the curve is edited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temp_chip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temp_index: Option<String>,
+    // Raises any duty above zero to at least this, on top of what the fans' calibration asks for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_duty: Option<Duty>,
+    #[serde(flatten)]
+    pub curve: Curve,
+}
+
+// A named set of curves, e.g. a quiet one for calls. Pwms it has no curve for follow the default curves.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    #[serde(default)]
+    pub curves: Vec<CurveConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AlertConfig {
+    // Run through `sh -c` with FANCONTROL_SENSOR, FANCONTROL_ALERT and FANCONTROL_RESOLVED set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    // Skipped when stderr goes to journald, the log line already puts the alert in the journal
+    #[serde(default = "enabled")]
+    pub syslog: bool,
+    // Desktop notification on the session D-Bus of every logged in user, found under /run/user/UID/bus
+    #[serde(default)]
+    pub desktop: bool,
+}
+
+// The control socket of the running daemon. Whoever can open it can change fan speeds, so it's
+// only readable and writable by root and `group` unless `mode` says otherwise.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SocketConfig {
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_socket_path")]
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(default = "default_socket_mode")]
+    pub mode: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            interval_ms: DEFAULT_INTERVAL_MS,
+            pairings: Vec::new(),
+            curves: Vec::new(),
+            profile: None,
+            transition_secs: DEFAULT_TRANSITION_SECS,
+            profiles: Vec::new(),
+            rules: Vec::new(),
+            safety: SafetyConfig::default(),
+            alerts: AlertConfig::default(),
+            socket: SocketConfig::default(),
+        }
+    }
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self { enabled: true, path: default_socket_path(), group: None, mode: default_socket_mode() }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        return toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+
+    // For commands that save the config afterwards: a missing file starts from the defaults, but one that
+    // can't be read or parsed is an error rather than being replaced with them.
+    pub fn load_for_update(path: &Path) -> io::Result<Self> {
+        match Self::load(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            result => result,
+        }
+    }
+
+    // Only for reading, a broken config is logged and the defaults used. Never save what this returns.
+    pub fn load_or_default(path: &Path) -> Self {
+        match Self::load(path) {
+            Ok(config) => config,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(e) => {
+                log::error!("Error reading {}: {e}", path.display());
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let contents = toml::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Written next to the config and renamed over it, an interrupted save leaves the old one intact
+        let file_name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file", path.display())))?;
+        let temp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+        let mut file = fs::File::create(&temp_path)?;
+        let result = file.write_all(contents.as_bytes()).and_then(|_| file.sync_all()).and_then(|_| fs::rename(&temp_path, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+
+        return result;
+    }
+
+    pub fn set_pairings_for(&mut self, hwmon: &Hwmon) {
+        self.pairings.retain(|p| !p.chip.matches(hwmon));
+
+        for fan in hwmon.fans.iter() {
+            if let Some(pwm) = &fan.paired_pwm {
+                self.pairings.push(Pairing {
+                    chip: ChipRef::new(hwmon),
+                    fan_index: fan.index,
+                    pwm_index: pwm.index.clone(),
+                    confidence: fan.pair_confidence,
+                    calibration: fan.calibration.clone(),
+                });
+            }
+        }
+    }
+
+    pub fn apply_pairings(&self, hwmons: &mut [Hwmon]) -> usize {
+        let mut applied = 0;
+
+        for hwmon in hwmons.iter_mut() {
+            let pairings: Vec<&Pairing> = self.pairings.iter().filter(|p| p.chip.matches(hwmon)).collect();
+
+            for pairing in pairings {
+                let pwm = match hwmon.pwms.iter().find(|p| p.index == pairing.pwm_index) {
+                    Some(p) => p.clone(),
+                    None => {
+                        log::warn!("{}: saved pwm{} no longer exists", hwmon.id, pairing.pwm_index);
+                        continue;
+                    }
+                };
+
+                match hwmon.fans.iter_mut().find(|f| f.index == pairing.fan_index) {
+                    Some(fan) => {
+                        fan.pair_with(pwm, pairing.confidence);
+                        if let Some(calibration) = &pairing.calibration {
+                            fan.set_calibration(calibration.clone());
+                        }
+                        applied += 1;
+                    }
+                    None => log::warn!("{}: saved fan{} no longer exists", hwmon.id, pairing.fan_index),
+                }
+            }
+        }
+
+        return applied;
+    }
+
+    // The curve for the whole chip, not one of its single pwm curves.
+    pub fn curve_for(&self, hwmon: &Hwmon) -> Option<&CurveConfig> {
+        self.curves.iter().find(|c| c.chip.matches(hwmon) && c.pwm_index.is_none())
+    }
+
+    // The curve that drives a pwm, its own or else the chip's.
+    pub fn curve_for_pwm(&self, hwmon: &Hwmon, pwm_index: &str) -> Option<&CurveConfig> {
+        find_curve(&self.curves, hwmon, pwm_index)
+    }
+
+    // Like `curve_for_pwm` but from `profile`, falling back to the default curves for pwms the profile leaves out.
+    pub fn profile_curve_for_pwm(&self, profile: &str, hwmon: &Hwmon, pwm_index: &str) -> Option<&CurveConfig> {
+        let curves = self.profiles.iter().find(|p| p.name == profile).map(|p| p.curves.as_slice()).unwrap_or_default();
+        find_curve(curves, hwmon, pwm_index).or_else(|| self.curve_for_pwm(hwmon, pwm_index))
+    }
+
+    pub fn has_profile(&self, name: &str) -> bool {
+        name == DEFAULT_PROFILE || self.profiles.iter().any(|p| p.name == name)
+    }
+
+    pub fn profile_names(&self) -> Vec<&str> {
+        let mut names = vec![DEFAULT_PROFILE];
+        names.extend(self.profiles.iter().map(|p| p.name.as_str()));
+        return names;
+    }
+
+    // The configured starting profile, `default` when it isn't set or doesn't exist.
+    pub fn default_profile(&self) -> &str {
+        match self.profile.as_deref() {
+            Some(name) if self.has_profile(name) => name,
+            Some(name) => {
+                log::warn!("There is no profile named {name}, using the default curves");
+                DEFAULT_PROFILE
+            }
+            None => DEFAULT_PROFILE,
+        }
+    }
+
+    // Replaces every curve of the chip, including the single pwm ones.
+    pub fn set_curve_for(&mut self, hwmon: &Hwmon, source: SourceConfig, curve: Curve) {
+        self.curves.retain(|c| !c.chip.matches(hwmon));
+        self.curves.push(CurveConfig { chip: ChipRef::new(hwmon), pwm_index: None, source: Some(source), temp_chip: None, temp_index: None, min_duty: None, curve });
+    }
+
+    // Returns the new curve, e.g. to give it a `min_duty`.
+    pub fn set_pwm_curve_for(&mut self, hwmon: &Hwmon, pwm_index: &str, source: SourceConfig, curve: Curve) -> &mut CurveConfig {
+        self.curves.retain(|c| !(c.chip.matches(hwmon) && c.pwm_index.as_deref() == Some(pwm_index)));
+        self.curves.push(CurveConfig { chip: ChipRef::new(hwmon), pwm_index: Some(pwm_index.to_string()), source: Some(source), temp_chip: None, temp_index: None, min_duty: None, curve });
+        return self.curves.last_mut().unwrap();
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    pub fn transition(&self) -> Duration {
+        Duration::from_secs(self.transition_secs)
+    }
+}
+
+impl CurveConfig {
+    // The saved source, or the single temp an older config names on `hwmon` or on `temp_chip`.
+    pub fn source_for(&self, hwmon: &Hwmon) -> Option<SourceConfig> {
+        if let Some(source) = &self.source {
+            return Some(source.clone());
+        }
+
+        let index = self.temp_index.as_ref()?;
+        let chip = self.temp_chip.clone().unwrap_or_else(|| hwmon.id.clone());
+        return Some(SourceConfig::single(TempRef { chip, temp: format!("temp{index}"), weight: 1.0 }));
+    }
+}
+
+impl ChipRef {
+    pub fn new(hwmon: &Hwmon) -> Self {
+        Self { chip: hwmon.id.clone(), hwmon_name: None, device_path: None }
+    }
+
+    pub fn matches(&self, hwmon: &Hwmon) -> bool {
+        if !self.chip.is_empty() {
+            return self.chip == hwmon.id;
+        }
+
+        // Older configs saved the name and the resolved device path, or the hwmonN path without a device
+        let address = hwmon.device.as_ref().map(|d| d.address.as_str()).unwrap_or(hwmon.dir_name());
+        let legacy_address = self.device_path.as_ref().and_then(|p| p.file_name()).and_then(|f| f.to_str());
+        return self.hwmon_name.as_deref() == Some(hwmon.name.as_str()) && legacy_address == Some(address);
+    }
+}
+
+fn default_interval_ms() -> u64 {
+    DEFAULT_INTERVAL_MS
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self { command: None, syslog: true, desktop: false }
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn default_transition_secs() -> u64 {
+    DEFAULT_TRANSITION_SECS
+}
+
+// A pwm's own curve in `curves`, or else the chip-wide one.
+fn find_curve<'a>(curves: &'a [CurveConfig], hwmon: &Hwmon, pwm_index: &str) -> Option<&'a CurveConfig> {
+    curves.iter()
+        .find(|c| c.chip.matches(hwmon) && c.pwm_index.as_deref() == Some(pwm_index))
+        .or_else(|| curves.iter().find(|c| c.chip.matches(hwmon) && c.pwm_index.is_none()))
+}
+
+fn default_socket_path() -> PathBuf {
+    PathBuf::from(DEFAULT_SOCKET_PATH)
+}
+
+fn default_socket_mode() -> u32 {
+    0o660
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fake_sysfs::FakeHwmonTree, fan_service::FanService, hwmon_service::HwmonService, units::{Duty, Rpm}};
+
+    #[test]
+    fn pairings_and_curves_survive_a_restart_that_renumbers_hwmon() {
+        let tree = FakeHwmonTree::new();
+        tree.chip(0, "nct6775").with_device("platform", "nct6775.656", "nct6775").with_fan(1, 800).with_fan(2, 900).with_temp(1, 40000).with_pwm(1, 128, 5).with_pwm(2, 128, 5);
+        let config_path = tree.root().join("fancontrol-rs.toml");
+
+        let mut service = HwmonService::new(tree.root().to_path_buf()).unwrap();
+        service.initialize_hwmons();
+        let hwmon = &mut service.hwmons[0];
+        let pwm = hwmon.pwms.iter().find(|p| p.index == "2").unwrap().clone();
+        let fan = hwmon.fans.iter_mut().find(|f| f.index == 1).unwrap();
+        fan.pair_with(pwm, Some(0.75));
+        fan.set_calibration(FanCalibration { stall_duty: Duty::new(40), start_duty: Duty::new(70), max_rpm: Rpm::new(1900), table: Vec::new() });
+
+        let mut config = Config::default();
+        config.set_pairings_for(hwmon);
+        config.set_curve_for(hwmon, SourceConfig::single(TempRef::new(hwmon, &hwmon.temps[0])), Curve::parse("30:80,70:255").unwrap());
+        config.save(&config_path).unwrap();
+
+        fs::rename(tree.root().join("hwmon0"), tree.root().join("hwmon4")).unwrap();
+        tree.chip(0, "nct6775").with_device("platform", "nct6775.2592", "nct6775").with_fan(1, 700).with_pwm(2, 128, 5);
+
+        let config = Config::load(&config_path).unwrap();
+        let mut service = HwmonService::new(tree.root().to_path_buf()).unwrap();
+        service.initialize_hwmons();
+        assert_eq!(service.load_pairings(&config), 1);
+
+        let chip = service.find("nct6775:nct6775@platform:nct6775.656").unwrap();
+        let service = FanService::new(service, config);
+        let hwmon = &service.hwmons[chip];
+        assert_eq!(hwmon.dir_name(), "hwmon4");
+        assert!(!service.hwmons[0].has_pairings());
+        let fan = hwmon.fans.iter().find(|f| f.index == 1).unwrap();
+        assert_eq!(fan.paired_pwm.as_ref().map(|p| p.index.as_str()), Some("2"));
+        assert_eq!(fan.pair_confidence, Some(0.75));
+
+        let control_loop = &service.control_loops_for(chip)[0];
+        assert_eq!(control_loop.source.temps[0].0.index, "1");
+        assert_eq!(control_loop.pwms.len(), 1);
+        assert_eq!(control_loop.min_duty, Duty::new(70));
+        assert_eq!(fan.max_speed_rpm, Rpm::new(1900));
+    }
+
+    #[test]
+    fn configs_from_before_chip_ids_still_match() {
+        let tree = FakeHwmonTree::new();
+        tree.chip(2, "it87").with_device("platform", "it87.2608", "it87").with_fan(1, 800).with_temp(1, 40000).with_pwm(1, 128, 5);
+
+        let config: Config = toml::from_str(r#"
+            [[pairings]]
+            hwmon_name = "it87"
+            device_path = "/sys/devices/platform/it87.2608"
+            fan_index = 1
+            pwm_index = "1"
+
+            [[curves]]
+            chip = "it87:it87@platform:it87.2608"
+            temp_index = "1"
+            points = [{ temp = 30.0, duty = 80 }, { temp = 70.0, duty = 255 }]
+        "#).unwrap();
+
+        let mut service = HwmonService::new(tree.root().to_path_buf()).unwrap();
+        service.initialize_hwmons();
+        assert_eq!(service.load_pairings(&config), 1);
+
+        let mut config = config;
+        config.set_pairings_for(&service.hwmons[0]);
+        let saved = toml::to_string(&config).unwrap();
+        assert!(saved.contains(r#"chip = "it87:it87@platform:it87.2608""#));
+        assert!(!saved.contains("device_path"));
+        assert!(saved.contains(r#"temp_index = "1""#));
+
+        let service = FanService::new(service, config);
+        assert_eq!(service.control_loops().len(), 1);
+    }
+
+    #[test]
+    fn a_broken_config_is_never_replaced_with_the_defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fancontrol-rs.toml");
+        assert_eq!(Config::load_for_update(&path).unwrap().interval_ms, DEFAULT_INTERVAL_MS);
+
+        let config = Config { interval_ms: 500, ..Config::default() };
+        config.save(&path).unwrap();
+        assert_eq!(Config::load_for_update(&path).unwrap().interval_ms, 500);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        fs::write(&path, "interval_ms = \"fast\"").unwrap();
+        assert_eq!(Config::load_for_update(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(Config::load_or_default(&path).interval_ms, DEFAULT_INTERVAL_MS);
+    }
+}
@@ -0,0 +1,317 @@
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+
+use crate::{curve::Curve, hwmon::{fans::Fan, pwm::Pwm}, temp_source::TempSource, units::{Duty, Temperature}, watchdog::{Failsafe, Fault, Watchdog}};
+
+pub struct ControlLoopHandle {
+    stop_flag: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+pub struct ControlLoop {
+    pub source: TempSource,
+    pub curve: Curve,
+    pub pwms: Vec<Pwm>,
+    pub fans: Vec<Fan>,
+    pub interval: Duration,
+    pub min_duty: Duty,
+    watchdog: Watchdog,
+    transition: Option<Transition>,
+}
+
+// Eases each pwm from the duty it had when the loop was built to the curve's, e.g. after a profile change.
+struct Transition {
+    // Same order as `pwms`, None when the duty couldn't be read
+    from: Vec<Option<Duty>>,
+    started: Instant,
+    duration: Duration,
+}
+
+// Duties that take the place of the curve for a while, e.g. set over the control socket.
+// The failsafe still forces full speed, an override never keeps a fan slow through a fault.
+#[derive(Default)]
+pub struct Overrides {
+    active: Vec<Override>,
+}
+
+#[derive(Clone)]
+pub struct Override {
+    pub pwm: Pwm,
+    pub duty: Duty,
+    pub until: Instant,
+}
+
+impl ControlLoop {
+    pub fn new(source: TempSource, curve: Curve, pwms: Vec<Pwm>, interval: Duration) -> Self {
+        Self { source, curve, pwms, fans: Vec::new(), interval, min_duty: Duty::OFF, watchdog: Watchdog::default(), transition: None }
+    }
+
+    // Raises non-zero curve duties to at least `min_duty` so calibrated fans never stall.
+    pub fn with_min_duty(mut self, min_duty: Duty) -> Self {
+        self.min_duty = min_duty;
+        return self;
+    }
+
+    // Fans driven by this loop, a failed read on any of them trips the failsafe.
+    pub fn with_fans(mut self, fans: Vec<Fan>) -> Self {
+        self.fans = fans;
+        return self;
+    }
+
+    pub fn with_watchdog(mut self, watchdog: Watchdog) -> Self {
+        self.watchdog = watchdog;
+        return self;
+    }
+
+    // Starts from the pwms' current duties instead of jumping straight to the curve. Call after the pwms are set.
+    pub fn easing_in(mut self, duration: Duration) -> Self {
+        if !duration.is_zero() {
+            let from = self.pwms.iter().map(|p| p.read_duty().ok()).collect();
+            self.transition = Some(Transition { from, started: Instant::now(), duration });
+        }
+        return self;
+    }
+
+    // Reads every source temp and the fans through the watchdog, returning the combined temperature to act on.
+    pub fn check(&mut self) -> Result<Temperature, Fault> {
+        if self.source.temps.is_empty() {
+            let pwms = self.pwms.iter().map(|p| p.name.as_str()).collect::<Vec<_>>().join(", ");
+            return Err(Fault::MissingSource { pwms });
+        }
+
+        let mut readings = Vec::with_capacity(self.source.temps.len());
+        for (temp, _) in self.source.temps.iter() {
+            readings.push(self.watchdog.check_temp(temp)?);
+        }
+
+        for fan in self.fans.iter() {
+            self.watchdog.check_fan(fan)?;
+        }
+
+        return Ok(self.source.combine(&readings));
+    }
+
+    pub fn drives(&self, pwm: &Pwm) -> bool {
+        self.pwms.iter().any(|p| p.is_same_channel(pwm))
+    }
+
+    // A pwm that can't be written is a fault too, the fans behind it are no longer under control.
+    // Overridden pwms get their override, the curve's duty is still returned.
+    pub fn apply(&self, temp: Temperature, overrides: &Overrides) -> Result<Duty, Fault> {
+        let duty = match self.curve.duty_for(temp) {
+            Duty::OFF => Duty::OFF,
+            duty => duty.max(self.min_duty),
+        };
+
+        for (i, pwm) in self.pwms.iter().enumerate() {
+            // Easing up from a stopped fan still skips the duties it would stall at
+            let eased = match self.transition.as_ref().map(|t| t.duty(i, duty)) {
+                Some(eased) if duty != Duty::OFF => eased.max(self.min_duty),
+                Some(eased) => eased,
+                None => duty,
+            };
+            let pwm_duty = overrides.duty_for(pwm).unwrap_or(eased);
+            pwm.set_duty(pwm_duty).map_err(|error| Fault::WriteFailed { pwm: pwm.name.clone(), error })?;
+        }
+
+        return Ok(duty);
+    }
+
+    pub fn full_speed(&self) {
+        for pwm in self.pwms.iter() {
+            if let Err(e) = pwm.set_duty(Duty::MAX) {
+                log::error!("Unable to force {} to full speed: {e}", pwm.name);
+            }
+        }
+    }
+
+    pub fn tick(&mut self) -> Result<Duty, Fault> {
+        match self.check().and_then(|temp| self.apply(temp, &Overrides::default())) {
+            Ok(duty) => Ok(duty),
+            Err(fault) => {
+                self.full_speed();
+                Err(fault)
+            }
+        }
+    }
+
+    pub fn spawn(mut self) -> ControlLoopHandle {
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = Arc::clone(&stop_flag);
+
+        let thread = thread::spawn(move || {
+            let mut failsafe = Failsafe::default();
+
+            while !stop_flag_clone.load(Ordering::Relaxed) {
+                let result = self.tick();
+                failsafe.update(result.as_ref().err());
+                thread::sleep(self.interval);
+            }
+        });
+
+        return ControlLoopHandle { stop_flag, thread };
+    }
+}
+
+impl ControlLoopHandle {
+    // Waits for the current tick to finish so nothing writes a PWM after this returns.
+    pub fn stop(self) {
+        self.stop_flag.store(true, Ordering::Relaxed);
+        let _ = self.thread.join();
+    }
+}
+
+impl Transition {
+    fn duty(&self, pwm: usize, target: Duty) -> Duty {
+        let progress = self.started.elapsed().as_secs_f32() / self.duration.as_secs_f32();
+        let from = match self.from.get(pwm) {
+            Some(Some(from)) if progress < 1.0 => *from,
+            _ => return target,
+        };
+
+        let eased = f32::from(from.raw()) + (f32::from(target.raw()) - f32::from(from.raw())) * progress;
+        return Duty::new(eased.round() as u8);
+    }
+}
+
+impl Overrides {
+    // Replaces any earlier override of the same pwm. Fails for a duration too long to represent.
+    pub fn set(&mut self, pwm: Pwm, duty: Duty, duration: Duration) -> Result<(), String> {
+        let until = Instant::now().checked_add(duration).ok_or_else(|| format!("an override of {}s is too long", duration.as_secs()))?;
+        self.active.retain(|o| !o.pwm.is_same_channel(&pwm));
+        self.active.push(Override { pwm, duty, until });
+        return Ok(());
+    }
+
+    // Clears the override of `pwm`, or every override without one. Returns how many were cleared.
+    pub fn clear(&mut self, pwm: Option<&Pwm>) -> usize {
+        let before = self.active.len();
+        self.active.retain(|o| pwm.is_some_and(|p| !o.pwm.is_same_channel(p)));
+        return before - self.active.len();
+    }
+
+    pub fn get(&self, pwm: &Pwm) -> Option<&Override> {
+        self.active.iter().find(|o| o.pwm.is_same_channel(pwm) && o.until > Instant::now())
+    }
+
+    pub fn duty_for(&self, pwm: &Pwm) -> Option<Duty> {
+        self.get(pwm).map(|o| o.duty)
+    }
+
+    // Drops the overrides that ran out and returns them.
+    pub fn expire(&mut self) -> Vec<Override> {
+        let now = Instant::now();
+        let (expired, active) = self.active.drain(..).partition(|o| o.until <= now);
+        self.active = active;
+        return expired;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::hwmon::{backend::MockBackend, temp::Temp};
+
+    const CHIP: &str = "/sys/class/hwmon/hwmon1";
+
+    fn setup(duty: &str) -> (Arc<MockBackend>, ControlLoop) {
+        let backend = Arc::new(MockBackend::new()
+            .with_chip(CHIP, "nct6775")
+            .with_attribute(CHIP, "temp1_input", "60000")
+            .with_attribute(CHIP, "pwm1", duty)
+            .with_attribute(CHIP, "pwm1_enable", "1")
+            .with_attribute(CHIP, "pwm2", "90")
+            .with_attribute(CHIP, "pwm2_enable", "1"));
+        let temp = Temp::new(backend.clone(), PathBuf::from(CHIP)).with_index("1".into());
+        let pwm = Pwm::new(backend.clone(), PathBuf::from(CHIP)).with_index("1".into()).with_name("pwm1".into());
+        let curve = Curve::parse("30:0,40:100,60:220").unwrap();
+
+        let control_loop = ControlLoop::new(TempSource::single(temp), curve, vec![pwm], Duration::from_secs(1)).with_min_duty(Duty::new(60));
+        return (backend, control_loop);
+    }
+
+    fn written(backend: &MockBackend) -> u8 {
+        backend.get(Path::new(CHIP), "pwm1").unwrap().parse().unwrap()
+    }
+
+    fn other_pwm(backend: &Arc<MockBackend>) -> Pwm {
+        Pwm::new(backend.clone(), PathBuf::from(CHIP)).with_index("2".into()).with_name("pwm2".into())
+    }
+
+    #[test]
+    fn eases_from_the_current_duty_to_the_curve() {
+        let (backend, control_loop) = setup("20");
+        let mut control_loop = control_loop.easing_in(Duration::from_secs(10));
+        let hot = Temperature::from_celsius(60.0);
+
+        // Starting below the floor, the eased duty is raised to it rather than stalling the fan
+        assert_eq!(control_loop.apply(hot, &Overrides::default()).unwrap(), Duty::new(220));
+        assert_eq!(written(&backend), 60);
+
+        let started_ago = |secs| Instant::now().checked_sub(Duration::from_secs(secs)).unwrap();
+        control_loop.transition.as_mut().unwrap().started = started_ago(5);
+        control_loop.apply(hot, &Overrides::default()).unwrap();
+        assert!((118..=122).contains(&written(&backend)), "halfway from 20 to 220, got {}", written(&backend));
+
+        control_loop.transition.as_mut().unwrap().started = started_ago(10);
+        control_loop.apply(hot, &Overrides::default()).unwrap();
+        assert_eq!(written(&backend), 220);
+
+        // Easing down to a stopped fan passes through the duties below the floor
+        let (backend, control_loop) = setup("200");
+        let mut control_loop = control_loop.easing_in(Duration::from_secs(10));
+        control_loop.transition.as_mut().unwrap().started = started_ago(9);
+        assert_eq!(control_loop.apply(Temperature::from_celsius(30.0), &Overrides::default()).unwrap(), Duty::OFF);
+        assert_eq!(written(&backend), 20);
+    }
+
+    #[test]
+    fn overrides_hold_a_duty_until_cleared_or_expired() {
+        let (backend, control_loop) = setup("128");
+        let (pwm, other) = (control_loop.pwms[0].clone(), other_pwm(&backend));
+        let hot = Temperature::from_celsius(60.0);
+        let mut overrides = Overrides::default();
+
+        // Only the loop's own pwm is driven, the daemon refuses to override any other
+        assert!(control_loop.drives(&pwm));
+        assert!(!control_loop.drives(&other));
+        // A duration past what an Instant can hold is refused instead of panicking
+        assert!(overrides.set(pwm.clone(), Duty::new(80), Duration::MAX).is_err());
+
+        overrides.set(pwm.clone(), Duty::new(80), Duration::from_secs(60)).unwrap();
+        overrides.set(other.clone(), Duty::new(40), Duration::from_secs(60)).unwrap();
+        assert_eq!(control_loop.apply(hot, &overrides).unwrap(), Duty::new(220));
+        assert_eq!(written(&backend), 80);
+        assert_eq!(backend.get(Path::new(CHIP), "pwm2").as_deref(), Some("90"));
+
+        assert_eq!(overrides.clear(Some(&other)), 1);
+        assert_eq!(overrides.duty_for(&pwm), Some(Duty::new(80)));
+        assert_eq!(overrides.clear(None), 1);
+        control_loop.apply(hot, &overrides).unwrap();
+        assert_eq!(written(&backend), 220);
+
+        overrides.set(pwm.clone(), Duty::new(80), Duration::from_secs(60)).unwrap();
+        overrides.active[0].until = Instant::now();
+        assert_eq!(overrides.duty_for(&pwm), None);
+        assert_eq!(overrides.expire().len(), 1);
+        assert!(overrides.active.is_empty());
+    }
+
+    #[test]
+    fn failsafe_wins_over_an_override() {
+        let (backend, mut control_loop) = setup("128");
+        let mut overrides = Overrides::default();
+        overrides.set(control_loop.pwms[0].clone(), Duty::new(30), Duration::from_secs(60)).unwrap();
+
+        let temp = control_loop.check().unwrap();
+        control_loop.apply(temp, &overrides).unwrap();
+        assert_eq!(written(&backend), 30);
+
+        // As the daemon does it, a fault skips `apply` and forces every pwm of the loop to full speed
+        backend.set(Path::new(CHIP), "temp1_input", "garbage");
+        assert!(control_loop.check().is_err());
+        control_loop.full_speed();
+        assert_eq!(written(&backend), 255);
+    }
+}
@@ -0,0 +1,338 @@
+use std::{ffi::CString, fs, io::{self, BufRead, BufReader, Write}, os::unix::{fs::PermissionsExt, net::{UnixListener, UnixStream}}, path::{Path, PathBuf}, sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError}, thread};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{config::SocketConfig, units::Duty};
+
+// Lines waiting for one client, a subscriber that stops reading is dropped once this many pile up
+const MAX_QUEUED_LINES: usize = 32;
+
+// What a client can ask the daemon, one JSON object per line, e.g.
+// {"id": 1, "method": "override", "params": {"pwm": "nct6775/pwm2", "duty": 200, "minutes": 10}}
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Method {
+    // Every chip with its current readings, like `list --format json`
+    ListSensors,
+    // The duty of every pwm, whether a curve drives it and any override
+    GetDuty,
+    // Holds one pwm as CHIP/PWM, or every pwm the curves drive without one, at `duty` for `minutes`
+    Override {
+        #[serde(default)]
+        pwm: Option<String>,
+        duty: Duty,
+        minutes: u64,
+    },
+    ClearOverride {
+        #[serde(default)]
+        pwm: Option<String>,
+    },
+    // The active profile, why it is active and every profile there is
+    GetProfile,
+    // Runs a whole profile, for `minutes` or until changed again, ahead of the rules. Without a name the rules pick again
+    SetProfile {
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        minutes: Option<u64>,
+    },
+    Reload,
+    // Readings are pushed as {"method": "readings", "params": [...]} after every control loop tick
+    Subscribe,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Request {
+    #[serde(default)]
+    pub id: Value,
+    #[serde(flatten)]
+    pub method: Method,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Response {
+    pub id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Notification {
+    pub method: String,
+    pub params: Value,
+}
+
+// Sends lines back to one connected client, cheap to clone and keep for subscriptions.
+#[derive(Clone)]
+pub struct Replier {
+    lines: SyncSender<String>,
+}
+
+// A request waiting for the daemon to answer it.
+pub struct Call {
+    pub id: Value,
+    pub method: Method,
+    pub reply: Replier,
+}
+
+// The listening socket, handing every request to whoever polls `calls`. The socket file is removed on drop.
+pub struct ControlSocket {
+    path: PathBuf,
+    calls: Receiver<Call>,
+}
+
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    next_id: u64,
+}
+
+impl Replier {
+    pub fn respond(&self, id: Value, result: Result<Value, String>) {
+        let response = match result {
+            Ok(result) => Response { id, result: Some(result), error: None },
+            Err(error) => Response { id, result: None, error: Some(error) },
+        };
+        self.send(&response);
+    }
+
+    // Returns false once the client has gone away or stopped reading.
+    pub fn notify(&self, method: &str, params: Value) -> bool {
+        self.send(&Notification { method: method.to_string(), params })
+    }
+
+    fn send<T: Serialize>(&self, message: &T) -> bool {
+        match serde_json::to_string(message) {
+            Ok(line) => match self.lines.try_send(line) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("A control socket client stopped reading, dropping it");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            },
+            Err(e) => {
+                log::warn!("Unable to serialize a control socket message: {e}");
+                true
+            }
+        }
+    }
+}
+
+impl ControlSocket {
+    // Replaces a socket left behind by a daemon that didn't stop cleanly, but not one that is still answering.
+    pub fn bind(config: &SocketConfig) -> io::Result<Self> {
+        let path = config.path.clone();
+        if UnixStream::connect(&path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("another daemon is listening on {}", path.display())));
+        }
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(config.mode))?;
+        if let Some(group) = &config.group {
+            std::os::unix::fs::chown(&path, None, Some(group_id(group)?))?;
+        }
+
+        let (calls_tx, calls) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let calls = calls_tx.clone();
+                        thread::spawn(move || serve_connection(stream, calls));
+                    }
+                    Err(e) => log::warn!("Control socket connection failed: {e}"),
+                }
+            }
+        });
+
+        return Ok(Self { path, calls });
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Requests received since the last call, never blocks.
+    pub fn calls(&self) -> impl Iterator<Item = Call> + '_ {
+        self.calls.try_iter()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Client {
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        let writer = UnixStream::connect(path)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        return Ok(Self { reader, writer, next_id: 1 });
+    }
+
+    // Sends `method` and waits for its answer, skipping any readings pushed in between.
+    pub fn call(&mut self, method: Method) -> Result<Value, String> {
+        let id = Value::from(self.next_id);
+        self.next_id += 1;
+
+        let request = serde_json::to_string(&Request { id: id.clone(), method }).map_err(|e| e.to_string())?;
+        writeln!(self.writer, "{request}").map_err(|e| e.to_string())?;
+
+        loop {
+            let line = self.read_line()?;
+            let response: Response = match serde_json::from_str(&line) {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+            if response.id != id {
+                continue;
+            }
+
+            return match response.error {
+                Some(error) => Err(error),
+                None => Ok(response.result.unwrap_or(Value::Null)),
+            };
+        }
+    }
+
+    // The next pushed message after a `Subscribe`.
+    pub fn next_notification(&mut self) -> Result<Notification, String> {
+        loop {
+            let line = self.read_line()?;
+            if let Ok(notification) = serde_json::from_str::<Notification>(&line) {
+                return Ok(notification);
+            }
+        }
+    }
+
+    fn read_line(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Err("the daemon closed the connection".to_string()),
+            Ok(_) => Ok(line),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+// Requests are read here and answered by the daemon through a writer thread, so pushed readings never wait on a slow reader.
+fn serve_connection(stream: UnixStream, calls: Sender<Call>) {
+    let mut writer = match stream.try_clone() {
+        Ok(w) => w,
+        Err(e) => {
+            log::warn!("Control socket connection failed: {e}");
+            return;
+        }
+    };
+
+    let (lines_tx, lines) = mpsc::sync_channel::<String>(MAX_QUEUED_LINES);
+    thread::spawn(move || {
+        for line in lines {
+            if writeln!(writer, "{line}").is_err() {
+                break;
+            }
+        }
+    });
+
+    let reply = Replier { lines: lines_tx };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(l) if l.trim().is_empty() => continue,
+            Ok(l) => l,
+            Err(_) => break,
+        };
+
+        match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let call = Call { id: request.id, method: request.method, reply: reply.clone() };
+                if calls.send(call).is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                let id = serde_json::from_str::<Value>(&line).ok().and_then(|v| v.get("id").cloned()).unwrap_or(Value::Null);
+                reply.respond(id, Err(format!("invalid request: {e}")));
+            }
+        }
+    }
+}
+
+fn group_id(group: &str) -> io::Result<u32> {
+    let name = CString::new(group).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: name is a valid C string and the entry is only read before the next getgr* call
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no group named {group}")));
+    }
+
+    // SAFETY: checked for null above
+    return Ok(unsafe { (*entry).gr_gid });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_requests_and_pushes_readings_to_subscribers() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SocketConfig { path: dir.path().join("fancontrol.sock"), mode: 0o600, ..SocketConfig::default() };
+        let socket = ControlSocket::bind(&config).unwrap();
+        assert_eq!(fs::metadata(socket.path()).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(ControlSocket::bind(&config).is_err());
+
+        // Stands in for the daemon's poll loop
+        let daemon = thread::spawn(move || {
+            let mut handled = 0;
+            while handled < 3 {
+                for call in socket.calls() {
+                    handled += 1;
+                    match call.method {
+                        Method::Override { duty, minutes: 0, .. } => call.reply.respond(call.id, Err(format!("can't hold {duty} for 0 minutes"))),
+                        Method::Subscribe => {
+                            call.reply.respond(call.id, Ok(Value::Bool(true)));
+                            call.reply.notify("readings", serde_json::json!([{"id": "nct6775"}]));
+                        }
+                        method => call.reply.respond(call.id, Ok(serde_json::to_value(method).unwrap())),
+                    }
+                }
+                thread::sleep(std::time::Duration::from_millis(10));
+            }
+        });
+
+        let mut client = Client::connect(&config.path).unwrap();
+        assert_eq!(client.call(Method::GetDuty).unwrap(), serde_json::json!({"method": "get_duty"}));
+        let error = client.call(Method::Override { pwm: Some("nct6775/pwm2".into()), duty: Duty::new(200), minutes: 0 }).unwrap_err();
+        assert!(error.contains("0 minutes"));
+        assert_eq!(client.call(Method::Subscribe).unwrap(), Value::Bool(true));
+        assert_eq!(client.next_notification().unwrap().method, "readings");
+
+        writeln!(client.writer, "{{\"id\": 7, \"method\": \"explode\"}}").unwrap();
+        let response: Response = serde_json::from_str(&client.read_line().unwrap()).unwrap();
+        assert_eq!(response.id, Value::from(7));
+        assert!(response.error.unwrap().starts_with("invalid request"));
+
+        daemon.join().unwrap();
+        assert!(!config.path.exists());
+    }
+
+    #[test]
+    fn drops_subscribers_that_stop_reading() {
+        let (lines, _unread) = mpsc::sync_channel(MAX_QUEUED_LINES);
+        let reply = Replier { lines };
+
+        for _ in 0..MAX_QUEUED_LINES {
+            assert!(reply.notify("readings", Value::Null));
+        }
+        assert!(!reply.notify("readings", Value::Null));
+    }
+}
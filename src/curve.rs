@@ -0,0 +1,148 @@
+use std::fmt::{self, Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::units::{Duty, Temperature};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct CurvePoint {
+    // °C, as written in the config
+    pub temp: f32,
+    pub duty: Duty,
+}
+
+// Points are kept sorted by temperature, a curve read from the config goes through `checked` too.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(try_from = "CurvePoints")]
+pub struct Curve {
+    pub points: Vec<CurvePoint>,
+}
+
+// The config's form of a curve, its points as written.
+#[derive(Deserialize)]
+struct CurvePoints {
+    points: Vec<CurvePoint>,
+}
+
+impl Curve {
+    pub fn new(mut points: Vec<CurvePoint>) -> Self {
+        points.sort_by(|a, b| a.temp.total_cmp(&b.temp));
+        Self { points }
+    }
+
+    // Like `new`, for points from the user. Fails without any point or with a temperature that isn't a number.
+    pub fn checked(points: Vec<CurvePoint>) -> Result<Self, String> {
+        if points.is_empty() {
+            return Err("curve needs at least one point".into());
+        }
+        if let Some(point) = points.iter().find(|p| !p.temp.is_finite()) {
+            return Err(format!("invalid temperature '{}'", point.temp));
+        }
+
+        return Ok(Self::new(points));
+    }
+
+    // Parses "temp:duty" pairs separated by commas, e.g. "30:80,50:150,70:255" or "30:30%,70:100%".
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut points = Vec::new();
+
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (temp, duty) = pair.split_once(':').ok_or_else(|| format!("expected temp:duty, got '{pair}'"))?;
+            let temp = temp.trim().parse::<f32>().map_err(|e| format!("invalid temperature '{temp}': {e}"))?;
+            let duty = duty.parse::<Duty>().map_err(|e| format!("invalid duty '{}': {e}", duty.trim()))?;
+            points.push(CurvePoint { temp, duty });
+        }
+
+        return Self::checked(points);
+    }
+
+    pub fn duty_for(&self, temp: Temperature) -> Duty {
+        let temp = temp.celsius();
+        let (first, last) = match (self.points.first(), self.points.last()) {
+            (Some(f), Some(l)) => (f, l),
+            _ => return Duty::MAX,
+        };
+
+        if temp <= first.temp {
+            return first.duty;
+        }
+        if temp >= last.temp {
+            return last.duty;
+        }
+
+        for window in self.points.windows(2) {
+            let (low, high) = (window[0], window[1]);
+            if temp > high.temp {
+                continue;
+            }
+
+            let span = high.temp - low.temp;
+            if span <= 0.0 {
+                return high.duty;
+            }
+
+            let ratio = (temp - low.temp) / span;
+            let (low_duty, high_duty) = (f32::from(low.duty.raw()), f32::from(high.duty.raw()));
+            let duty = low_duty + ratio * (high_duty - low_duty);
+            return Duty::new(duty.round().clamp(0.0, 255.0) as u8);
+        }
+
+        return last.duty;
+    }
+}
+
+impl TryFrom<CurvePoints> for Curve {
+    type Error = String;
+
+    fn try_from(curve: CurvePoints) -> Result<Self, Self::Error> {
+        Self::checked(curve.points)
+    }
+}
+
+impl Display for Curve {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let points: Vec<String> = self.points.iter().map(|p| format!("{}:{}", p.temp, p.duty)).collect();
+        write!(f, "{}", points.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_between_points_and_clamps_outside() {
+        let curve = Curve::parse("70:255, 30:80,50:150").unwrap();
+
+        let duty_for = |celsius: f32| curve.duty_for(Temperature::from_celsius(celsius)).raw();
+
+        assert_eq!(duty_for(20.0), 80);
+        assert_eq!(duty_for(40.0), 115);
+        assert_eq!(duty_for(60.0), 203);
+        assert_eq!(duty_for(90.0), 255);
+        assert_eq!(Curve::parse("30:0%,70:100%").unwrap().to_string(), "30:0,70:255");
+    }
+
+    #[test]
+    fn parse_rejects_bad_points() {
+        assert!(Curve::parse("").is_err());
+        assert!(Curve::parse("30").is_err());
+        assert!(Curve::parse("30:300").is_err());
+        assert!(Curve::parse("30:-10").is_err());
+        assert!(Curve::parse("NaN:100").is_err());
+        assert!(Curve::parse("inf:100").is_err());
+    }
+
+    #[test]
+    fn curves_from_the_config_are_sorted_and_checked() {
+        let curve: Curve = toml::from_str("points = [{ temp = 70.0, duty = 255 }, { temp = 30.0, duty = 80 }, { temp = 50.0, duty = 150 }]").unwrap();
+        assert_eq!(curve.to_string(), "30:80,50:150,70:255");
+        assert_eq!(curve.duty_for(Temperature::from_celsius(40.0)), Duty::new(115));
+
+        let config: crate::config::Config = toml::from_str("[[curves]]\nchip = \"nct\"\ntemp_index = \"1\"\npoints = [{ temp = 60.0, duty = 200 }, { temp = 40.0, duty = 50 }]").unwrap();
+        assert_eq!(config.curves[0].curve.to_string(), "40:50,60:200");
+
+        assert!(toml::from_str::<Curve>("points = []").is_err());
+        assert!(toml::from_str::<Curve>("points = [{ temp = nan, duty = 80 }]").is_err());
+    }
+}
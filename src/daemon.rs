@@ -0,0 +1,413 @@
+use std::{error::Error, net::SocketAddr, path::Path, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread, time::{Duration, Instant}};
+
+use log::{error, info, warn};
+use sd_notify::NotifyState;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+
+use serde_json::{json, Value};
+
+use fancontrol::{alerts::{Alert, Alerter}, config::{Config, SocketConfig}, control_loop::{ControlLoop, Overrides}, control_socket::{Call, ControlSocket, Method, Replier}, fan_monitor::FanMonitor, fan_service::FanService, hwmon::{hwmon::Hwmon, pwm::Pwm, pwm_state::PwmStateGuard}, hwmon_service::{self, HwmonService}, metrics::Metrics, profiles::{self, Environment, Reason}, snapshot, watchdog::Failsafe};
+
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// The longest anything requested over the socket may last, a day
+const MAX_REQUEST_MINUTES: u64 = 24 * 60;
+
+// Where every load and reload comes from.
+struct Setup<'a> {
+    config_path: &'a Path,
+    sysfs_root: &'a Path,
+    // Replaces the config's `profile`
+    profile: Option<&'a str>,
+}
+
+struct DaemonState {
+    // Builds the loops again whenever the active profile changes
+    service: FanService,
+    profile_reason: Reason,
+    loops: Vec<ControlLoop>,
+    monitors: Vec<FanMonitor>,
+    alerter: Alerter,
+    interval: Duration,
+    // Shared with the metrics server, which reads them on every scrape
+    hwmons: Arc<Vec<Hwmon>>,
+    socket: SocketConfig,
+}
+
+// What clients of the control socket have asked for, kept across reloads.
+#[derive(Default)]
+struct Requests {
+    overrides: Overrides,
+    subscribers: Vec<Replier>,
+    // Picked over the socket or with `run --profile`, ahead of the rules until the time runs out
+    profile: Option<(String, Option<Instant>)>,
+    // Set when a request changed what the fans should do, so the next tick doesn't wait for the interval
+    tick_now: bool,
+}
+
+pub fn run(config_path: &Path, sysfs_root: &Path, metrics_listen: Option<SocketAddr>, profile: Option<&str>) -> i32 {
+    let setup = Setup { config_path, sysfs_root, profile };
+
+    let terminate = Arc::new(AtomicBool::new(false));
+    let reload = Arc::new(AtomicBool::new(false));
+
+    for (signal, flag) in [(SIGTERM, &terminate), (SIGINT, &terminate), (SIGHUP, &reload)] {
+        if let Err(e) = signal_hook::flag::register(signal, Arc::clone(flag)) {
+            error!("Failed to register signal handler: {e}");
+            return 1;
+        }
+    }
+
+    let mut state = match load_state(&setup) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to start with {}: {e}", config_path.display());
+            return 1;
+        }
+    };
+
+    if state.loops.is_empty() {
+        error!("No curves with paired fans in {}, run the pairing wizard first", config_path.display());
+        return 1;
+    }
+
+    if let Some(name) = profile && !state.service.config.has_profile(name) {
+        error!("There is no profile named {name} in {}", config_path.display());
+        return 1;
+    }
+
+    let metrics = Arc::new(Metrics::new(Arc::clone(&state.hwmons)));
+    if let Some(addr) = metrics_listen {
+        match metrics.serve(addr) {
+            Ok(addr) => info!("Serving metrics on http://{addr}/metrics"),
+            Err(e) => {
+                error!("Unable to serve metrics on {addr}: {e}");
+                return 1;
+            }
+        }
+    }
+
+    // Fan control doesn't depend on the socket, a daemon without one still runs
+    let control_socket = match state.socket.enabled {
+        true => ControlSocket::bind(&state.socket)
+            .inspect(|s| info!("Listening for requests on {}", s.path().display()))
+            .inspect_err(|e| warn!("Unable to listen on {}: {e}", state.socket.path.display()))
+            .ok(),
+        false => None,
+    };
+    // Selected like a profile picked over the socket, so it wins over the rules until a client clears it
+    let mut requests = Requests { profile: profile.map(|name| (name.to_string(), None)), ..Requests::default() };
+
+    let _pwm_state_guard = PwmStateGuard::new();
+    let watchdog_interval = watchdog_interval();
+    let mut last_watchdog = Instant::now();
+
+    info!("Controlling {} curve(s) every {:?}", state.loops.len(), state.interval);
+    notify(&[NotifyState::Ready]);
+
+    let mut failsafe = Failsafe::default();
+
+    while !terminate.load(Ordering::Relaxed) {
+        for expired in requests.overrides.expire() {
+            info!("Override of {} ran out, back to its curve", expired.pwm.name);
+        }
+        update_profile(&mut state, &mut requests);
+        tick(&mut state, &mut failsafe, &metrics, &requests.overrides);
+        publish_readings(&state, &mut requests);
+
+        let next_tick = Instant::now() + state.interval;
+        requests.tick_now = false;
+        while Instant::now() < next_tick && !requests.tick_now && !terminate.load(Ordering::Relaxed) && !reload.load(Ordering::Relaxed) {
+            if let Some(interval) = watchdog_interval && last_watchdog.elapsed() >= interval {
+                notify(&[NotifyState::Watchdog]);
+                last_watchdog = Instant::now();
+            }
+
+            for call in control_socket.iter().flat_map(|s| s.calls()) {
+                handle_call(call, &mut state, &mut requests, &setup, &metrics);
+            }
+
+            thread::sleep(SIGNAL_POLL_INTERVAL);
+        }
+
+        if reload.swap(false, Ordering::Relaxed) {
+            let _ = reload_state(&mut state, &setup, &metrics);
+        }
+    }
+
+    info!("Stopping, restoring original PWM settings");
+    notify(&[NotifyState::Stopping]);
+
+    return 0;
+}
+
+// Applies the curves only when every loop's readings are trusted and every write lands, otherwise every managed fan goes to full speed.
+fn tick(state: &mut DaemonState, failsafe: &mut Failsafe, metrics: &Metrics, overrides: &Overrides) {
+    let readings: Vec<_> = state.loops.iter_mut().map(|l| l.check()).collect();
+    let mut temps = Vec::new();
+    let mut fault = None;
+
+    for reading in readings {
+        match reading {
+            Ok(temp) => temps.push(temp),
+            Err(f) => {
+                metrics.health.record_fault(&f);
+                fault.get_or_insert(f);
+            }
+        }
+    }
+
+    if fault.is_none() {
+        fault = state.loops.iter().zip(temps).find_map(|(control_loop, temp)| control_loop.apply(temp, overrides).err());
+        if let Some(f) = &fault {
+            metrics.health.record_fault(f);
+        }
+    }
+
+    if fault.is_some() {
+        state.loops.iter().for_each(|l| l.full_speed());
+    }
+
+    let fault = fault.as_ref();
+    metrics.health.set_failsafe(fault.is_some());
+    if failsafe.update(fault) {
+        let status = fault.map(|f| format!("Failsafe: {f}")).unwrap_or_else(|| "Controlling fans".into());
+        notify(&[NotifyState::Status(&status)]);
+
+        let alert = match fault {
+            Some(f) => Alert::new("failsafe", format!("{f}, all managed fans forced to full speed")),
+            None => Alert::resolved("failsafe", "readings are back to normal, resuming curve control".into()),
+        };
+        state.alerter.send(&alert);
+    }
+
+    for monitor in state.monitors.iter_mut() {
+        for alert in monitor.check() {
+            state.alerter.send(&alert);
+        }
+    }
+}
+
+// Switches to the profile the selection or the rules ask for, easing the duties over from the old curves.
+fn update_profile(state: &mut DaemonState, requests: &mut Requests) {
+    if let Some((name, Some(until))) = &requests.profile && *until <= Instant::now() {
+        info!("Profile {name} ran out, back to the rules");
+        requests.profile = None;
+    }
+
+    let config = &state.service.config;
+    let selected = requests.profile.as_ref().map(|(name, _)| name.as_str());
+    let (profile, reason) = profiles::choose(config, selected, &Environment::current(config));
+    state.profile_reason = reason;
+    if profile == state.service.profile {
+        return;
+    }
+
+    let previous = std::mem::replace(&mut state.service.profile, profile);
+    let loops: Vec<ControlLoop> = state.service.control_loops().into_iter()
+        .map(|l| l.easing_in(state.service.config.transition()))
+        .collect();
+
+    if loops.is_empty() {
+        warn!("Profile {} has no usable curves, staying on {previous}", state.service.profile);
+        state.service.profile = previous;
+        return;
+    }
+
+    info!("Switching from profile {previous} to {} ({}), controlling {} curve(s)", state.service.profile, state.profile_reason, loops.len());
+    state.loops = loops;
+}
+
+fn load_state(setup: &Setup) -> Result<DaemonState, Box<dyn Error>> {
+    let mut config = Config::load(setup.config_path)?;
+    if let Some(profile) = setup.profile {
+        config.profile = Some(profile.to_string());
+    }
+
+    let mut hwmon_service = HwmonService::new(setup.sysfs_root.to_path_buf())?;
+    hwmon_service.initialize_hwmons();
+    let fan_service = FanService::new(hwmon_service, config);
+
+    let monitors = fan_service.hwmons
+        .iter()
+        .map(FanMonitor::new)
+        .filter(|m| !m.is_empty())
+        .collect();
+
+    let loops = fan_service.control_loops();
+    let config = &fan_service.config;
+    let (alerter, interval, socket) = (Alerter::new(&config.alerts), config.interval(), config.socket.clone());
+    let hwmons = Arc::new(fan_service.hwmons.clone());
+    return Ok(DaemonState { service: fan_service, profile_reason: Reason::Default, loops, monitors, alerter, interval, hwmons, socket });
+}
+
+// The control socket isn't moved by a reload, it keeps the path and permissions it started with.
+// Stays on the active profile if the new config still has it.
+fn reload_state(state: &mut DaemonState, setup: &Setup, metrics: &Metrics) -> Result<usize, String> {
+    let config_path = setup.config_path;
+    let mut reloading = vec![NotifyState::Reloading];
+    if let Ok(now) = NotifyState::monotonic_usec_now() {
+        reloading.push(now);
+    }
+    notify(&reloading);
+
+    let result = match load_state(setup) {
+        Ok(new_state) if new_state.loops.is_empty() => Err("reloaded config has no curves, keeping the current one".to_string()),
+        Ok(mut new_state) => {
+            if new_state.service.set_profile(&state.service.profile).is_ok() {
+                new_state.loops = new_state.service.control_loops();
+                new_state.profile_reason = state.profile_reason.clone();
+            }
+
+            info!("Reloaded {}, controlling {} curve(s)", config_path.display(), new_state.loops.len());
+            metrics.set_hwmons(Arc::clone(&new_state.hwmons));
+            let curves = new_state.loops.len();
+            *state = new_state;
+            Ok(curves)
+        }
+        Err(e) => Err(format!("failed to reload {}: {e}, keeping the current config", config_path.display())),
+    };
+
+    if let Err(e) = &result {
+        warn!("{e}");
+    }
+    notify(&[NotifyState::Ready]);
+    return result;
+}
+
+fn handle_call(call: Call, state: &mut DaemonState, requests: &mut Requests, setup: &Setup, metrics: &Metrics) {
+    let result = match call.method {
+        Method::ListSensors => serde_json::to_value(snapshot::snapshot(&state.hwmons)).map_err(|e| e.to_string()),
+        Method::GetDuty => Ok(duties(state, &requests.overrides)),
+        Method::Override { pwm, duty, minutes } => request_duration(minutes)
+            .and_then(|duration| Ok((duration, managed_pwms(state, pwm.as_deref())?)))
+            .and_then(|(duration, pwms)| {
+                for pwm in pwms.iter() {
+                    requests.overrides.set(pwm.clone(), duty, duration)?;
+                    info!("Holding {} at {duty} for {minutes} min", pwm.name);
+                }
+                requests.tick_now = true;
+                Ok(json!({ "pwms": pwms.iter().map(|p| p.name.clone()).collect::<Vec<_>>(), "minutes": minutes }))
+            }),
+        Method::ClearOverride { pwm: None } => {
+            requests.tick_now = true;
+            Ok(json!({ "cleared": requests.overrides.clear(None) }))
+        }
+        Method::ClearOverride { pwm: Some(target) } => managed_pwms(state, Some(&target)).map(|pwms| {
+            requests.tick_now = true;
+            json!({ "cleared": requests.overrides.clear(pwms.first()) })
+        }),
+        Method::GetProfile => Ok(json!({
+            "active": state.service.profile,
+            "reason": state.profile_reason.to_string(),
+            "selected_for_secs": requests.profile.as_ref().and_then(|(_, until)| *until).map(|u| u.saturating_duration_since(Instant::now()).as_secs()),
+            "profiles": state.service.config.profile_names(),
+        })),
+        Method::SetProfile { name: Some(name), minutes } => {
+            if !state.service.config.has_profile(&name) {
+                Err(format!("no profile named {name}, there are {}", state.service.config.profile_names().join(", ")))
+            } else {
+                minutes.map(request_deadline).transpose().map(|until| {
+                    info!("Profile {name} selected{}", minutes.map(|m| format!(" for {m} min")).unwrap_or_default());
+                    requests.profile = Some((name.clone(), until));
+                    requests.tick_now = true;
+                    json!({ "profile": name, "minutes": minutes })
+                })
+            }
+        }
+        Method::SetProfile { name: None, .. } => {
+            info!("Profile selection cleared, the rules pick again");
+            requests.profile = None;
+            requests.tick_now = true;
+            Ok(json!({ "profile": null }))
+        }
+        Method::Reload => reload_state(state, setup, metrics).map(|curves| {
+            requests.tick_now = true;
+            json!({ "curves": curves })
+        }),
+        Method::Subscribe => {
+            requests.subscribers.push(call.reply.clone());
+            Ok(json!({ "interval_ms": state.interval.as_millis() as u64 }))
+        }
+    };
+
+    call.reply.respond(call.id, result);
+}
+
+// Anything a client asks to last for `minutes`, between a minute and a day.
+fn request_duration(minutes: u64) -> Result<Duration, String> {
+    if !(1..=MAX_REQUEST_MINUTES).contains(&minutes) {
+        return Err(format!("expected 1 to {MAX_REQUEST_MINUTES} minutes, got {minutes}"));
+    }
+
+    return minutes.checked_mul(60).map(Duration::from_secs).ok_or_else(|| format!("{minutes} minutes is too long"));
+}
+
+fn request_deadline(minutes: u64) -> Result<Instant, String> {
+    let duration = request_duration(minutes)?;
+    return Instant::now().checked_add(duration).ok_or_else(|| format!("{minutes} minutes is too long"));
+}
+
+// The pwm named by CHIP/PWM, or every pwm a curve drives without a target. Only those can be overridden,
+// nothing would put any other pwm back once the override runs out.
+fn managed_pwms(state: &DaemonState, target: Option<&str>) -> Result<Vec<Pwm>, String> {
+    let target = match target {
+        Some(t) => t,
+        None => return Ok(state.loops.iter().flat_map(|l| l.pwms.iter().cloned()).collect()),
+    };
+
+    let (chip, name) = target.rsplit_once('/').ok_or_else(|| format!("expected CHIP/PWM, got '{target}'"))?;
+    let hwmon = hwmon_service::find_chip(&state.hwmons, chip).map(|i| &state.hwmons[i]).ok_or_else(|| format!("no chip named {chip}"))?;
+    let pwm = hwmon.pwms.iter().find(|p| p.name == name).ok_or_else(|| format!("{chip} has no pwm output {name}"))?;
+
+    if !state.loops.iter().any(|l| l.drives(pwm)) {
+        return Err(format!("{target} isn't driven by any curve"));
+    }
+
+    return Ok(vec![pwm.clone()]);
+}
+
+fn duties(state: &DaemonState, overrides: &Overrides) -> Value {
+    let mut duties = Vec::new();
+
+    for hwmon in state.hwmons.iter() {
+        for pwm in hwmon.pwms.iter() {
+            let held = overrides.get(pwm).map(|o| json!({ "duty": o.duty, "remaining_secs": o.until.saturating_duration_since(Instant::now()).as_secs() }));
+            duties.push(json!({
+                "chip": hwmon.id,
+                "pwm": pwm.name,
+                "duty": pwm.read_duty().ok(),
+                "enable": pwm.read_enable().ok(),
+                "managed": state.loops.iter().any(|l| l.drives(pwm)),
+                "override": held,
+            }));
+        }
+    }
+
+    return Value::Array(duties);
+}
+
+fn publish_readings(state: &DaemonState, requests: &mut Requests) {
+    if requests.subscribers.is_empty() {
+        return;
+    }
+
+    match serde_json::to_value(snapshot::snapshot(&state.hwmons)) {
+        Ok(readings) => requests.subscribers.retain(|s| s.notify("readings", readings.clone())),
+        Err(e) => warn!("Unable to serialize readings: {e}"),
+    }
+}
+
+fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        return Some(Duration::from_micros(usec / 2));
+    }
+
+    return None;
+}
+
+fn notify(states: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, states) {
+        warn!("sd_notify failed: {e}");
+    }
+}
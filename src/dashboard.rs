@@ -0,0 +1,390 @@
+use std::{collections::{HashMap, VecDeque}, io::{self, Write}, path::PathBuf, time::Duration};
+
+use crossterm::{cursor::{Hide, MoveTo, Show}, event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers}, queue, style::{Attribute, Print, SetAttribute}, terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen}};
+
+use fancontrol::{control_loop::ControlLoopHandle, fan_service::FanService, hwmon::pwm_state::{self, PwmStateGuard}, hwmon_service::HwmonService, units::Duty};
+
+use crate::{commands::{self, Context}, program, terminal_utils};
+
+const HISTORY_LEN: usize = 40;
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+const NUDGE_STEP: i32 = 5;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const HELP: &str = "↑/↓ select pwm  ←/→ or -/+ nudge duty  a auto control  p pair  c edit curve  q quit";
+
+struct History {
+    values: VecDeque<f32>,
+}
+
+struct Line {
+    text: String,
+    selected: bool,
+}
+
+struct Dashboard {
+    service: FanService,
+    config_path: PathBuf,
+    history: HashMap<String, History>,
+    selected: usize,
+    control_loop: Option<(usize, Vec<ControlLoopHandle>)>,
+    status: String,
+}
+
+// Leaves raw mode and the alternate screen when dropped, including on panic.
+struct TerminalGuard;
+
+pub fn run(context: &Context) -> i32 {
+    let _pwm_state_guard = PwmStateGuard::new();
+    pwm_state::restore_on_signal();
+
+    let config = match commands::load_config_for_update(&context.config_path) {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+    let mut service = match HwmonService::new(context.sysfs_root.clone()) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Unable to discover hwmon chips: {e}");
+            return commands::exit_code(&e);
+        }
+    };
+    service.initialize_hwmons();
+    let mut service = FanService::new(service, config);
+
+    for hwmon in service.hwmons.iter_mut() {
+        hwmon.fans.sort_by_key(|f| f.index);
+        hwmon.temps.sort_by_key(|t| t.index.parse::<u32>().unwrap_or(0));
+        hwmon.pwms.sort_by_key(|p| p.index.parse::<u32>().unwrap_or(0));
+    }
+
+    if service.hwmons.is_empty() {
+        eprintln!("No hwmon chips found in {}", context.sysfs_root.display());
+        return commands::EXIT_NOT_FOUND;
+    }
+
+    let mut dashboard = Dashboard {
+        service,
+        config_path: context.config_path.clone(),
+        history: HashMap::new(),
+        selected: 0,
+        control_loop: None,
+        status: String::new(),
+    };
+
+    let result = dashboard.run();
+    dashboard.stop_control_loop();
+
+    match result {
+        Ok(_) => commands::EXIT_OK,
+        Err(e) => {
+            eprintln!("Dashboard failed: {e}");
+            commands::EXIT_ERROR
+        }
+    }
+}
+
+impl Dashboard {
+    fn run(&mut self) -> io::Result<()> {
+        let mut terminal_guard = Some(TerminalGuard::enter()?);
+
+        loop {
+            self.sample();
+            self.draw()?;
+
+            if !event::poll(REFRESH_INTERVAL)? {
+                continue;
+            }
+
+            let key = match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => key,
+                _ => continue,
+            };
+
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+                KeyCode::Up | KeyCode::Char('k') => self.selected = self.selected.saturating_sub(1),
+                KeyCode::Down | KeyCode::Char('j') => self.selected = (self.selected + 1).min(self.pwm_count().saturating_sub(1)),
+                KeyCode::Right | KeyCode::Char('+') | KeyCode::Char('=') => self.nudge(NUDGE_STEP),
+                KeyCode::Left | KeyCode::Char('-') => self.nudge(-NUDGE_STEP),
+                KeyCode::PageUp => self.nudge(NUDGE_STEP * 5),
+                KeyCode::PageDown => self.nudge(-NUDGE_STEP * 5),
+                KeyCode::Char('a') => self.toggle_control_loop(),
+                KeyCode::Char('p') | KeyCode::Char('c') => {
+                    // The wizards are line based, so hand the terminal back while they run
+                    terminal_guard.take();
+                    self.run_wizard(key);
+                    terminal_guard = Some(TerminalGuard::enter()?);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn sample(&mut self) {
+        let mut samples = Vec::new();
+
+        // Failed reads leave a gap in the history rather than a drop to 0
+        for hwmon in self.service.hwmons.iter() {
+            for fan in hwmon.fans.iter() {
+                if let Ok(rpm) = fan.get_speed() {
+                    samples.push((format!("{}/fan{}", hwmon.dir_name(), fan.index), rpm.get() as f32));
+                }
+            }
+            for temp in hwmon.temps.iter() {
+                if let Ok(reading) = temp.get_temp() {
+                    samples.push((format!("{}/temp{}", hwmon.dir_name(), temp.index), reading.celsius()));
+                }
+            }
+            for pwm in hwmon.pwms.iter() {
+                if let Ok(duty) = pwm.read_duty() {
+                    samples.push((format!("{}/{}", hwmon.dir_name(), pwm.name), f32::from(duty.raw())));
+                }
+            }
+        }
+
+        for (key, value) in samples {
+            self.history.entry(key).or_insert_with(History::new).push(value);
+        }
+    }
+
+    fn draw(&self) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let lines = self.lines();
+        let mut out = io::stdout().lock();
+
+        for (row, line) in lines.iter().take(usize::from(height)).enumerate() {
+            let text: String = line.text.chars().take(usize::from(width)).collect();
+            queue!(out, MoveTo(0, row as u16), Clear(ClearType::CurrentLine))?;
+
+            if line.selected {
+                queue!(out, SetAttribute(Attribute::Reverse), Print(text), SetAttribute(Attribute::Reset))?;
+            } else {
+                queue!(out, Print(text))?;
+            }
+        }
+
+        queue!(out, Clear(ClearType::FromCursorDown))?;
+        return out.flush();
+    }
+
+    fn lines(&self) -> Vec<Line> {
+        let mut lines = vec![Line::plain(format!("fancontrol  {HELP}")), Line::plain(String::new())];
+        let mut pwm_position = 0;
+
+        for (chip, hwmon) in self.service.hwmons.iter().enumerate() {
+            let controlled = matches!(self.control_loop, Some((c, _)) if c == chip);
+            lines.push(Line::plain(format!("{} [{}]{}", hwmon.id, hwmon.dir_name(), if controlled { "  [auto control]" } else { "" })));
+
+            for temp in hwmon.temps.iter() {
+                let key = format!("{}/temp{}", hwmon.dir_name(), temp.index);
+                lines.push(Line::plain(format!("    temp{:<3} {:<16} {:>10}  {}", temp.index, temp.label, temp.get_formatted_temp(), self.sparkline(&key))));
+            }
+
+            for fan in hwmon.fans.iter() {
+                let key = format!("{}/fan{}", hwmon.dir_name(), fan.index);
+                let pairing = fan.paired_pwm.as_ref().map(|p| format!("  -> {}", p.name)).unwrap_or_default();
+                lines.push(Line::plain(format!("    fan{:<4} {:<16} {:>10}  {}{pairing}", fan.index, fan.label, fan.get_formatted_speed(), self.sparkline(&key))));
+            }
+
+            for pwm in hwmon.pwms.iter() {
+                let key = format!("{}/{}", hwmon.dir_name(), pwm.name);
+                let duty = match pwm.read_duty() {
+                    Ok(duty) => format!("{:>4} ({:>3.0}%)", duty.raw(), duty.percent()),
+                    Err(e) => format!("{:<11}", e.reason()),
+                };
+                let mode = pwm.read_enable().map(|m| format!("mode {m}")).unwrap_or_default();
+                let selected = pwm_position == self.selected;
+                let marker = if selected { ">" } else { " " };
+
+                lines.push(Line {
+                    text: format!("  {marker} {:<21} {duty} {:<7} {}", pwm.name, mode, self.sparkline(&key)),
+                    selected,
+                });
+                pwm_position += 1;
+            }
+
+            lines.push(Line::plain(String::new()));
+        }
+
+        lines.push(Line::plain(self.status.clone()));
+        return lines;
+    }
+
+    fn sparkline(&self, key: &str) -> String {
+        self.history.get(key).map(|h| h.sparkline()).unwrap_or_default()
+    }
+
+    fn pwm_count(&self) -> usize {
+        self.service.hwmons.iter().map(|h| h.pwms.len()).sum()
+    }
+
+    // Chip and pwm index of the selected row.
+    fn selection(&self) -> Option<(usize, usize)> {
+        let mut position = 0;
+
+        for (chip, hwmon) in self.service.hwmons.iter().enumerate() {
+            if self.selected < position + hwmon.pwms.len() {
+                return Some((chip, self.selected - position));
+            }
+            position += hwmon.pwms.len();
+        }
+
+        return None;
+    }
+
+    fn nudge(&mut self, step: i32) {
+        let (chip, index) = match self.selection() {
+            Some(s) => s,
+            None => return,
+        };
+
+        if matches!(self.control_loop, Some((c, _)) if c == chip) {
+            self.status = "Auto control is running on this chip, press a to stop it first".into();
+            return;
+        }
+
+        let pwm = &self.service.hwmons[chip].pwms[index];
+        let duty = match pwm.read_duty() {
+            Ok(duty) => Duty::new((i32::from(duty.raw()) + step).clamp(0, 255) as u8),
+            Err(e) => {
+                self.status = format!("Unable to read {}: {e}", pwm.name);
+                return;
+            }
+        };
+
+        self.status = match pwm.set_duty(duty) {
+            Ok(_) => format!("{} set to {duty}", pwm.name),
+            Err(e) => format!("Unable to set {}: {e}", pwm.name),
+        };
+    }
+
+    fn toggle_control_loop(&mut self) {
+        if self.control_loop.is_some() {
+            self.stop_control_loop();
+            self.status = "Auto control stopped".into();
+            return;
+        }
+
+        let chip = match self.selection() {
+            Some((chip, _)) => chip,
+            None => return,
+        };
+
+        let hwmon = &self.service.hwmons[chip];
+        let control_loops = self.service.control_loops_for(chip);
+        self.status = match control_loops.as_slice() {
+            [] => format!("{} has no curve or paired fans, press p or c to set them up", hwmon.name),
+            [control_loop] => format!("Auto control of {} from {} with curve {}", hwmon.name, control_loop.source, control_loop.curve),
+            _ => format!("Auto control of {} with {} curves", hwmon.name, control_loops.len()),
+        };
+
+        if !control_loops.is_empty() {
+            self.control_loop = Some((chip, control_loops.into_iter().map(|l| l.spawn()).collect()));
+        }
+    }
+
+    fn stop_control_loop(&mut self) {
+        if let Some((_, handles)) = self.control_loop.take() {
+            handles.into_iter().for_each(|h| h.stop());
+        }
+    }
+
+    fn run_wizard(&mut self, key: KeyEvent) {
+        let chip = match self.selection() {
+            Some((chip, _)) => chip,
+            None => return,
+        };
+
+        if matches!(self.control_loop, Some((c, _)) if c == chip) {
+            self.stop_control_loop();
+        }
+
+        terminal_utils::clear_terminal();
+
+        if key.code == KeyCode::Char('p') {
+            let hwmon = &mut self.service.hwmons[chip];
+            let auto = terminal_utils::get_yes_no_selection_default_yes("Attempt auto pairing?");
+            program::pair_fans(hwmon, auto);
+            program::print_pairings(hwmon);
+            self.service.store_pairings(chip);
+        } else if let Some((source, curve)) = program::select_curve(&self.service.hwmons) {
+            self.service.set_curve(chip, source, curve);
+        }
+
+        program::save_config(&self.service.config, &self.config_path);
+        terminal_utils::wait_for_user_input();
+        self.status.clear();
+    }
+}
+
+impl History {
+    fn new() -> Self {
+        Self { values: VecDeque::with_capacity(HISTORY_LEN) }
+    }
+
+    fn push(&mut self, value: f32) {
+        if self.values.len() == HISTORY_LEN {
+            self.values.pop_front();
+        }
+        self.values.push_back(value);
+    }
+
+    fn sparkline(&self) -> String {
+        let min = self.values.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = self.values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let range = max - min;
+
+        self.values.iter()
+            .map(|v| {
+                let level = if range > 0.0 { (v - min) / range * (SPARKS.len() - 1) as f32 } else { 0.0 };
+                SPARKS[level.round() as usize]
+            })
+            .collect()
+    }
+}
+
+impl Line {
+    fn plain(text: String) -> Self {
+        Self { text, selected: false }
+    }
+}
+
+impl TerminalGuard {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let mut out = io::stdout();
+        queue!(out, EnterAlternateScreen, Hide)?;
+        out.flush()?;
+
+        return Ok(Self);
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let mut out = io::stdout();
+        let _ = queue!(out, Show, LeaveAlternateScreen);
+        let _ = out.flush();
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparkline_scales_to_history_range_and_drops_old_samples() {
+        let mut history = History::new();
+        for value in [10.0, 20.0, 30.0] {
+            history.push(value);
+        }
+        assert_eq!(history.sparkline(), "▁▅█");
+
+        for _ in 0..HISTORY_LEN {
+            history.push(5.0);
+        }
+        assert_eq!(history.sparkline(), "▁".repeat(HISTORY_LEN));
+    }
+}
@@ -0,0 +1,88 @@
+use std::{fmt::{self, Display, Formatter}, io, path::{Path, PathBuf}};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Anything that went wrong talking to a chip, always with the sysfs file it happened on.
+#[derive(Debug)]
+pub enum Error {
+    NotFound { path: PathBuf },
+    PermissionDenied { path: PathBuf },
+    Parse { path: PathBuf, raw: String },
+    // ENXIO/ENODEV, the device was unbound or unplugged while we were using it
+    DeviceGone { path: PathBuf },
+    // EIO, usually a flaky chip or bus that may answer on the next read
+    DeviceIo { path: PathBuf },
+    Io { path: PathBuf, source: io::Error },
+    // The original pwm values were put back for the process to exit, nothing may change them now
+    Exiting { path: PathBuf },
+}
+
+impl Error {
+    pub fn from_io(path: &Path, error: io::Error) -> Self {
+        let path = path.to_path_buf();
+
+        match (error.kind(), error.raw_os_error()) {
+            (_, Some(libc::ENXIO | libc::ENODEV)) => Error::DeviceGone { path },
+            (_, Some(libc::EIO)) => Error::DeviceIo { path },
+            (io::ErrorKind::NotFound, _) => Error::NotFound { path },
+            (io::ErrorKind::PermissionDenied, _) => Error::PermissionDenied { path },
+            _ => Error::Io { path, source: error },
+        }
+    }
+
+    // Short form for places that show a reading, e.g. a column in `list`.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Error::NotFound { .. } => "missing",
+            Error::PermissionDenied { .. } => "permission denied",
+            Error::Parse { .. } => "unreadable",
+            Error::DeviceGone { .. } => "device gone",
+            Error::DeviceIo { .. } => "I/O error",
+            Error::Io { .. } => "error",
+            Error::Exiting { .. } => "exiting",
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound { path } => write!(f, "{} does not exist", path.display()),
+            Error::PermissionDenied { path } => write!(f, "permission denied on {}, changing it needs root", path.display()),
+            Error::Parse { path, raw } => write!(f, "{} contains '{raw}', expected a number", path.display()),
+            Error::DeviceGone { path } => write!(f, "the device behind {} is gone", path.display()),
+            Error::DeviceIo { path } => write!(f, "I/O error on {}, the chip did not answer", path.display()),
+            Error::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            Error::Exiting { path } => write!(f, "not writing {}, the original value was restored on the way out", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_io_errors_to_what_happened_to_the_chip() {
+        let path = Path::new("/sys/class/hwmon/hwmon2/fan1_input");
+
+        assert!(matches!(Error::from_io(path, io::Error::from_raw_os_error(libc::ENXIO)), Error::DeviceGone { .. }));
+        assert!(matches!(Error::from_io(path, io::Error::from_raw_os_error(libc::ENODEV)), Error::DeviceGone { .. }));
+        assert!(matches!(Error::from_io(path, io::Error::from_raw_os_error(libc::EIO)), Error::DeviceIo { .. }));
+        assert!(matches!(Error::from_io(path, io::Error::from_raw_os_error(libc::ENOENT)), Error::NotFound { .. }));
+        assert!(matches!(Error::from_io(path, io::Error::from_raw_os_error(libc::EACCES)), Error::PermissionDenied { .. }));
+        assert!(matches!(Error::from_io(path, io::Error::other("boom")), Error::Io { .. }));
+
+        let error = Error::from_io(path, io::Error::from_raw_os_error(libc::EIO));
+        assert_eq!(error.to_string(), "I/O error on /sys/class/hwmon/hwmon2/fan1_input, the chip did not answer");
+    }
+}
@@ -0,0 +1,95 @@
+use std::{fs, os::unix::fs::symlink, path::{Path, PathBuf}};
+
+use tempfile::TempDir;
+
+// A temporary directory laid out like /sys/class/hwmon, for tests and --simulate.
+pub struct FakeHwmonTree {
+    dir: TempDir,
+}
+
+pub struct FakeChip {
+    path: PathBuf,
+}
+
+impl Default for FakeHwmonTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FakeHwmonTree {
+    pub fn new() -> Self {
+        Self { dir: TempDir::new().expect("unable to create temp dir") }
+    }
+
+    pub fn root(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn chip(&self, index: usize, name: &str) -> FakeChip {
+        let path = self.root().join(format!("hwmon{index}"));
+        fs::create_dir_all(&path).expect("unable to create chip dir");
+
+        return FakeChip { path }.with_file("name", name);
+    }
+}
+
+impl FakeChip {
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    // Links `device` to devices/{bus}/{address} with its subsystem and driver, like a real sysfs device.
+    pub fn with_device(self, bus: &str, address: &str, driver: &str) -> Self {
+        let root = self.path.parent().expect("chip dir has a parent");
+        let device = root.join("devices").join(bus).join(address);
+        let bus_dir = root.join("bus").join(bus);
+        let driver_dir = root.join("drivers").join(driver);
+
+        for dir in [&device, &bus_dir, &driver_dir] {
+            fs::create_dir_all(dir).expect("unable to create device dir");
+        }
+        symlink(&bus_dir, device.join("subsystem")).expect("unable to link subsystem");
+        symlink(&driver_dir, device.join("driver")).expect("unable to link driver");
+        symlink(&device, self.path.join("device")).expect("unable to link device");
+
+        return self;
+    }
+
+    pub fn with_file(self, file_name: &str, value: &str) -> Self {
+        self.write(file_name, value);
+        return self;
+    }
+
+    pub fn with_fan(self, index: usize, rpm: i32) -> Self {
+        self.with_file(&format!("fan{index}_input"), &rpm.to_string())
+    }
+
+    pub fn with_fan_label(self, index: usize, label: &str) -> Self {
+        self.with_file(&format!("fan{index}_label"), label)
+    }
+
+    pub fn with_temp(self, index: usize, millicelsius: i32) -> Self {
+        self.with_file(&format!("temp{index}_input"), &millicelsius.to_string())
+    }
+
+    pub fn with_temp_label(self, index: usize, label: &str) -> Self {
+        self.with_file(&format!("temp{index}_label"), label)
+    }
+
+    pub fn with_pwm(self, index: usize, duty: i32, enable: u8) -> Self {
+        self.with_file(&format!("pwm{index}"), &duty.to_string())
+            .with_file(&format!("pwm{index}_enable"), &enable.to_string())
+    }
+
+    // Writes through a rename so concurrent readers never see a half written file.
+    pub fn write(&self, file_name: &str, value: &str) {
+        let tmp_path = self.path.join(format!(".{file_name}.tmp"));
+        fs::write(&tmp_path, format!("{value}\n")).expect("unable to write fake sysfs file");
+        fs::rename(&tmp_path, self.path.join(file_name)).expect("unable to write fake sysfs file");
+    }
+
+    pub fn read(&self, file_name: &str) -> String {
+        fs::read_to_string(self.path.join(file_name)).unwrap_or_default().trim().to_string()
+    }
+}
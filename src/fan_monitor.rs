@@ -0,0 +1,176 @@
+use std::{fmt::{self, Display, Formatter}, mem};
+
+use crate::{alerts::Alert, hwmon::{fans::Fan, hwmon::Hwmon}, units::{Duty, Rpm}};
+
+// Consecutive bad checks before a fan is reported, so spin-up and spin-down don't count
+const STRIKES: u32 = 3;
+// A fan below this fraction of its calibrated speed counts as failing
+const SLOW_RATIO: f32 = 0.5;
+// Without a calibration, a fan is expected to spin from this duty up
+const UNCALIBRATED_MIN_DUTY: Duty = Duty::new(100);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FanProblem {
+    Stopped { duty: Duty },
+    Slow { rpm: Rpm, expected: Rpm, duty: Duty },
+    Alarm,
+    Fault,
+}
+
+struct WatchedFan {
+    fan: Fan,
+    sensor: String,
+    strikes: u32,
+    reported: Option<FanProblem>,
+}
+
+// Watches the paired fans of a chip for stalls and hardware alarms.
+pub struct FanMonitor {
+    fans: Vec<WatchedFan>,
+}
+
+impl FanMonitor {
+    pub fn new(hwmon: &Hwmon) -> Self {
+        let fans = hwmon.fans.iter()
+            .filter(|f| f.paired_pwm.is_some())
+            .map(|f| WatchedFan { fan: f.clone(), sensor: sensor_name(hwmon, f), strikes: 0, reported: None })
+            .collect();
+
+        Self { fans }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fans.is_empty()
+    }
+
+    // Returns an alert for every fan that started failing or recovered since the last check.
+    pub fn check(&mut self) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+
+        for watched in self.fans.iter_mut() {
+            match inspect(&watched.fan) {
+                Some(problem) => {
+                    watched.strikes += 1;
+                    let reported_kind = watched.reported.as_ref().map(mem::discriminant);
+                    if watched.strikes >= STRIKES && reported_kind != Some(mem::discriminant(&problem)) {
+                        alerts.push(Alert::new(&watched.sensor, problem.to_string()));
+                        watched.reported = Some(problem);
+                    }
+                }
+                None => {
+                    watched.strikes = 0;
+                    if watched.reported.take().is_some() {
+                        alerts.push(Alert::resolved(&watched.sensor, "fan is running normally again".into()));
+                    }
+                }
+            }
+        }
+
+        return alerts;
+    }
+}
+
+// Checks a single reading of a paired fan against its pwm and calibration.
+pub fn inspect(fan: &Fan) -> Option<FanProblem> {
+    if fan.has_fault() {
+        return Some(FanProblem::Fault);
+    }
+    if fan.has_alarm() {
+        return Some(FanProblem::Alarm);
+    }
+
+    let pwm = fan.paired_pwm.as_ref()?;
+    // pwmN_enable 0 means no speed control, i.e. full speed
+    // Failed reads are the watchdog's business, there is nothing to compare here
+    let duty = match pwm.read_enable() {
+        Ok(0) => Duty::MAX,
+        _ => pwm.read_duty().ok()?,
+    };
+    let rpm = fan.get_speed().ok()?;
+
+    let calibration = match &fan.calibration {
+        Some(c) => c,
+        None if rpm == Rpm::default() && duty >= UNCALIBRATED_MIN_DUTY => return Some(FanProblem::Stopped { duty }),
+        None => return None,
+    };
+
+    if rpm == Rpm::default() && duty >= calibration.min_duty() {
+        return Some(FanProblem::Stopped { duty });
+    }
+
+    let expected = calibration.expected_rpm(duty).unwrap_or_default();
+    if rpm > Rpm::default() && (rpm.get() as f32) < expected.get() as f32 * SLOW_RATIO {
+        return Some(FanProblem::Slow { rpm, expected, duty });
+    }
+
+    return None;
+}
+
+fn sensor_name(hwmon: &Hwmon, fan: &Fan) -> String {
+    if fan.label.is_empty() {
+        return format!("{}/fan{}", hwmon.id, fan.index);
+    }
+
+    return format!("{}/fan{} ({})", hwmon.id, fan.index, fan.label);
+}
+
+impl Display for FanProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FanProblem::Stopped { duty } => write!(f, "fan reports 0 RPM at duty {duty}"),
+            FanProblem::Slow { rpm, expected, duty } => write!(f, "fan runs at {rpm} at duty {duty}, expected about {expected}"),
+            FanProblem::Alarm => write!(f, "fan alarm is set"),
+            FanProblem::Fault => write!(f, "fan fault is set"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::*;
+    use crate::hwmon::{backend::MockBackend, calibration::{CalibrationPoint, FanCalibration}};
+
+    const CHIP: &str = "/sys/class/hwmon/hwmon3";
+
+    #[test]
+    fn reports_stopped_slow_and_alarmed_fans_once_and_their_recovery() {
+        let backend = Arc::new(MockBackend::new()
+            .with_chip(CHIP, "it87")
+            .with_device(CHIP, "platform", "it87.2608", "it87")
+            .with_attribute(CHIP, "fan1_input", "1000")
+            .with_attribute(CHIP, "pwm1", "128")
+            .with_attribute(CHIP, "pwm1_enable", "1"));
+        let chip = PathBuf::from(CHIP);
+
+        let mut hwmon = Hwmon::new(backend.clone(), chip.clone(), "it87".into());
+        hwmon.initialize().unwrap();
+        let pwm = hwmon.pwms[0].clone();
+        let table = vec![CalibrationPoint { duty: Duty::OFF, rpm: Rpm::new(0) }, CalibrationPoint { duty: Duty::MAX, rpm: Rpm::new(2000) }];
+        hwmon.fans[0].pair_with(pwm, None);
+        hwmon.fans[0].set_calibration(FanCalibration { stall_duty: Duty::new(40), start_duty: Duty::new(60), max_rpm: Rpm::new(2000), table });
+
+        let mut monitor = FanMonitor::new(&hwmon);
+        assert!(monitor.check().is_empty());
+
+        backend.set(&chip, "fan1_input", "0");
+        assert!(monitor.check().is_empty());
+        assert!(monitor.check().is_empty());
+        let alerts = monitor.check();
+        assert_eq!(alerts, [Alert::new("it87:it87@platform:it87.2608/fan1", "fan reports 0 RPM at duty 128".into())]);
+        assert!(monitor.check().is_empty());
+
+        backend.set(&chip, "fan1_input", "300");
+        let alerts: Vec<_> = (0..3).flat_map(|_| monitor.check()).collect();
+        assert_eq!(alerts.len(), 1);
+        assert!(alerts[0].message.contains("expected about 1004 RPM"));
+
+        backend.set(&chip, "fan1_input", "1000");
+        assert!(monitor.check()[0].resolved);
+
+        backend.set(&chip, "fan1_alarm", "1");
+        let alerts: Vec<_> = (0..3).flat_map(|_| monitor.check()).collect();
+        assert_eq!(alerts, [Alert::new("it87:it87@platform:it87.2608/fan1", "fan alarm is set".into())]);
+    }
+}
@@ -1,10 +1,236 @@
+use std::ptr;
+
+use crate::{config::{Config, CurveConfig}, control_loop::ControlLoop, curve::Curve, hwmon::{fans::Fan, hwmon::Hwmon, pwm::Pwm, temp::Temp}, hwmon_service::HwmonService, temp_source::{SourceConfig, TempSource}, units::Duty, watchdog::Watchdog};
+
+// Fans and pwms from every chip together with the pairings and curves that drive them.
+// A curve's pwms are all on one chip but its temps can be on any, e.g. a Super-I/O fan header following k10temp.
 pub struct FanService {
-    pub fans: Vec<Fan>,
-    pub pwms: Vec<Pwm>,
+    pub hwmons: Vec<Hwmon>,
+    pub config: Config,
+    // The profile `control_loops` builds from, editing curves always changes the default one
+    pub profile: String,
 }
 
 impl FanService {
-    pub fn new(fans: Vec<Fan>, pwms: Vec<Pwm>) -> Self {
-        Self {fans, pwms }
+    // Takes the chips found by `hwmon_service` and applies the saved pairings to them.
+    pub fn new(mut hwmon_service: HwmonService, config: Config) -> Self {
+        hwmon_service.load_pairings(&config);
+        let profile = config.default_profile().to_string();
+        Self { hwmons: hwmon_service.hwmons, config, profile }
+    }
+
+    pub fn set_profile(&mut self, name: &str) -> Result<(), String> {
+        if !self.config.has_profile(name) {
+            return Err(format!("no profile named {name}, there are {}", self.config.profile_names().join(", ")));
+        }
+
+        self.profile = name.to_string();
+        return Ok(());
+    }
+
+    pub fn fans(&self) -> impl Iterator<Item = (&Hwmon, &Fan)> {
+        self.hwmons.iter().flat_map(|h| h.fans.iter().map(move |f| (h, f)))
+    }
+
+    pub fn pwms(&self) -> impl Iterator<Item = (&Hwmon, &Pwm)> {
+        self.hwmons.iter().flat_map(|h| h.pwms.iter().map(move |p| (h, p)))
+    }
+
+    pub fn temps(&self) -> impl Iterator<Item = (&Hwmon, &Temp)> {
+        self.hwmons.iter().flat_map(|h| h.temps.iter().map(move |t| (h, t)))
+    }
+
+    // Chips with both fans and pwms, the only ones pairing can do anything with.
+    pub fn pairable(&self) -> Vec<usize> {
+        self.hwmons.iter().enumerate()
+            .filter(|(_, h)| !h.fans.is_empty() && !h.pwms.is_empty())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    pub fn has_pairings(&self) -> bool {
+        self.hwmons.iter().any(|h| h.has_pairings())
+    }
+
+    // Copies the chip's current pairings and calibrations into the config.
+    pub fn store_pairings(&mut self, chip: usize) {
+        self.config.set_pairings_for(&self.hwmons[chip]);
+    }
+
+    // Drives the paired pwms of `chip` from temps on any chip.
+    pub fn set_curve(&mut self, chip: usize, source: SourceConfig, curve: Curve) {
+        self.config.set_curve_for(&self.hwmons[chip], source, curve);
+    }
+
+    // The temps the curve of `chip` follows, looked up on whichever chips the curve names.
+    pub fn curve_source(&self, chip: usize) -> Option<TempSource> {
+        let hwmon = &self.hwmons[chip];
+        self.config.curve_for(hwmon)?.source_for(hwmon)?.resolve(&self.hwmons)
+    }
+
+    // One loop per curve of the chip in the active profile, each driving the paired pwms that curve applies to.
+    pub fn control_loops_for(&self, chip: usize) -> Vec<ControlLoop> {
+        let hwmon = &self.hwmons[chip];
+        let mut curves: Vec<(&CurveConfig, Vec<Pwm>)> = Vec::new();
+        let mut loops = Vec::new();
+
+        for pwm in hwmon.paired_pwms() {
+            let curve_config = match self.config.profile_curve_for_pwm(&self.profile, hwmon, &pwm.index) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            match curves.iter_mut().find(|(c, _)| ptr::eq(*c, curve_config)) {
+                Some((_, pwms)) => pwms.push(pwm),
+                None => curves.push((curve_config, vec![pwm])),
+            }
+        }
+
+        for (curve_config, pwms) in curves {
+            // The pwms are still in manual mode at their last duty, a loop that always faults puts them at full speed
+            let source = match curve_config.source_for(hwmon).and_then(|s| s.resolve(&self.hwmons)) {
+                Some(s) => s,
+                None => {
+                    log::error!("{}: the temps of the curve for {} are missing, running it at full speed", hwmon.id, pwms.iter().map(|p| p.name.as_str()).collect::<Vec<_>>().join(", "));
+                    TempSource::missing()
+                }
+            };
+
+            let fans: Vec<Fan> = hwmon.fans.iter()
+                .filter(|f| f.paired_pwm.as_ref().is_some_and(|p| pwms.iter().any(|q| q.index == p.index)))
+                .cloned()
+                .collect();
+
+            // One duty goes to every pwm of the loop, so it must keep the most demanding fan spinning
+            let min_duty = fans.iter()
+                .filter_map(|f| f.calibration.as_ref())
+                .map(|c| c.min_duty())
+                .chain(curve_config.min_duty)
+                .max()
+                .unwrap_or(Duty::OFF);
+
+            loops.push(ControlLoop::new(source, curve_config.curve.clone(), pwms, self.config.interval())
+                .with_min_duty(min_duty)
+                .with_fans(fans)
+                .with_watchdog(Watchdog::new(&self.config.safety)));
+        }
+
+        return loops;
+    }
+
+    // Every loop of every chip.
+    pub fn control_loops(&self) -> Vec<ControlLoop> {
+        (0..self.hwmons.len()).flat_map(|chip| self.control_loops_for(chip)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Profile, fake_sysfs::FakeHwmonTree, temp_source::{Aggregate, TempRef}, units::Temperature, watchdog::Fault};
+
+    #[test]
+    fn curve_on_one_chip_follows_temps_on_others() {
+        let tree = FakeHwmonTree::new();
+        tree.chip(0, "nct6775").with_device("platform", "nct6775.656", "nct6775").with_fan(1, 800).with_temp(1, 30000).with_pwm(1, 128, 5);
+        tree.chip(1, "k10temp").with_device("pci", "0000:00:18.3", "k10temp").with_temp(1, 65000).with_temp_label(1, "Tctl");
+
+        let mut hwmon_service = HwmonService::new(tree.root().to_path_buf()).unwrap();
+        hwmon_service.initialize_hwmons();
+        let mut service = FanService::new(hwmon_service, Config::default());
+        assert_eq!(service.pairable(), [0]);
+        assert_eq!(service.temps().count(), 2);
+
+        let pwm = service.hwmons[0].pwms[0].clone();
+        service.hwmons[0].fans[0].pair_with(pwm, None);
+        service.store_pairings(0);
+        let source = SourceConfig {
+            aggregate: Aggregate::Max,
+            temps: vec![TempRef::new(&service.hwmons[0], &service.hwmons[0].temps[0]), TempRef::new(&service.hwmons[1], &service.hwmons[1].temps[0])],
+        };
+        service.set_curve(0, source, Curve::parse("40:50,70:200").unwrap());
+        assert_eq!(service.config.curves[0].source.as_ref().unwrap().temps[1].chip, "k10temp:k10temp@pci:0000:00:18.3");
+
+        let config_path = tree.root().join("fancontrol-rs.toml");
+        service.config.save(&config_path).unwrap();
+
+        let mut hwmon_service = HwmonService::new(tree.root().to_path_buf()).unwrap();
+        hwmon_service.initialize_hwmons();
+        let service = FanService::new(hwmon_service, Config::load(&config_path).unwrap());
+
+        let mut loops = service.control_loops();
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0].source.to_string(), "max of temp1, Tctl");
+        assert_eq!(loops[0].pwms[0].name, "pwm1");
+        let temp = loops[0].check().unwrap();
+        assert_eq!(temp.celsius(), 65.0);
+        assert_eq!(loops[0].curve.duty_for(temp), Duty::new(175));
+    }
+
+    #[test]
+    fn profiles_replace_curves_and_fall_back_to_the_default_ones() {
+        let tree = FakeHwmonTree::new();
+        tree.chip(0, "nct6775").with_device("platform", "nct6775.656", "nct6775")
+            .with_fan(1, 800).with_fan(2, 900).with_temp(1, 50000).with_pwm(1, 128, 5).with_pwm(2, 128, 5);
+
+        let mut hwmon_service = HwmonService::new(tree.root().to_path_buf()).unwrap();
+        hwmon_service.initialize_hwmons();
+        let mut service = FanService::new(hwmon_service, Config::default());
+        for i in 0..2 {
+            let pwm = service.hwmons[0].pwms.iter().find(|p| p.index == (i + 1).to_string()).unwrap().clone();
+            service.hwmons[0].fans.iter_mut().find(|f| f.index == i + 1).unwrap().pair_with(pwm, None);
+        }
+        service.store_pairings(0);
+        let source = SourceConfig::single(TempRef::new(&service.hwmons[0], &service.hwmons[0].temps[0]));
+        service.set_curve(0, source.clone(), Curve::parse("40:50,70:200").unwrap());
+
+        // silent only has a curve of its own for pwm2, pwm1 keeps the default one
+        let mut silent = service.config.curves[0].clone();
+        silent.pwm_index = Some("2".into());
+        silent.min_duty = Some(Duty::new(90));
+        silent.curve = Curve::parse("40:0,70:100").unwrap();
+        service.config.profiles.push(Profile { name: "silent".into(), curves: vec![silent] });
+        service.config.profile = Some("silent".into());
+
+        let config_path = tree.root().join("fancontrol-rs.toml");
+        service.config.save(&config_path).unwrap();
+        let mut hwmon_service = HwmonService::new(tree.root().to_path_buf()).unwrap();
+        hwmon_service.initialize_hwmons();
+        let mut service = FanService::new(hwmon_service, Config::load(&config_path).unwrap());
+        assert_eq!(service.profile, "silent");
+
+        let mut loops = service.control_loops();
+        loops.sort_by_key(|l| l.pwms[0].index.clone());
+        assert_eq!(loops.len(), 2);
+        assert_eq!((loops[0].pwms[0].name.as_str(), loops[0].min_duty), ("pwm1", Duty::OFF));
+        assert_eq!((loops[1].pwms[0].name.as_str(), loops[1].min_duty), ("pwm2", Duty::new(90)));
+        assert_eq!(loops[1].curve.duty_for(Temperature::from_celsius(55.0)), Duty::new(50));
+
+        assert!(service.set_profile("performance").is_err());
+        service.set_profile("default").unwrap();
+        let loops = service.control_loops();
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0].pwms.len(), 2);
+    }
+
+    #[test]
+    fn curve_following_a_missing_temp_runs_its_fans_at_full_speed() {
+        let tree = FakeHwmonTree::new();
+        let chip = tree.chip(0, "nct6775").with_device("platform", "nct6775.656", "nct6775").with_fan(1, 800).with_temp(1, 30000).with_pwm(1, 128, 1);
+
+        let mut hwmon_service = HwmonService::new(tree.root().to_path_buf()).unwrap();
+        hwmon_service.initialize_hwmons();
+        let mut service = FanService::new(hwmon_service, Config::default());
+        let pwm = service.hwmons[0].pwms[0].clone();
+        service.hwmons[0].fans[0].pair_with(pwm, None);
+        service.store_pairings(0);
+        let gone = TempRef { chip: "k10temp:k10temp@pci:0000:00:18.3".into(), temp: "Tctl".into(), weight: 1.0 };
+        service.set_curve(0, SourceConfig::single(gone), Curve::parse("40:50,70:200").unwrap());
+
+        let mut loops = service.control_loops();
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0].source.to_string(), "missing temps");
+        assert!(matches!(loops[0].tick(), Err(Fault::MissingSource { pwms }) if pwms == "pwm1"));
+        assert_eq!(chip.read("pwm1"), "255");
     }
 }
@@ -0,0 +1,243 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use crate::{error::{Error, Result}, path_helpers::ReadTrimmed};
+
+pub struct ChipInfo {
+    pub path: PathBuf,
+    pub name: String,
+}
+
+// Where the `device` link of a chip points, this stays the same when hwmonN is renumbered.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceInfo {
+    pub bus: Option<String>,
+    pub address: String,
+    pub driver: Option<String>,
+    // Relative to /sys, e.g. "devices/platform/nct6775.656"
+    pub path: PathBuf,
+}
+
+// Everything that touches hardware goes through a backend, chips are identified by their path.
+pub trait HwmonBackend: Send + Sync {
+    fn enumerate_chips(&self) -> Result<Vec<ChipInfo>>;
+    fn list_attributes(&self, chip: &Path) -> Result<Vec<String>>;
+    fn read_attribute(&self, chip: &Path, attribute: &str) -> Result<String>;
+    fn write_attribute(&self, chip: &Path, attribute: &str, value: &str) -> Result<()>;
+    fn device_info(&self, chip: &Path) -> Option<DeviceInfo>;
+
+    fn read_fan_rpm(&self, chip: &Path, index: &str) -> Result<u32> {
+        let attribute = format!("fan{index}_input");
+        parse_attribute(chip, &attribute, self.read_attribute(chip, &attribute)?)
+    }
+
+    fn read_temp(&self, chip: &Path, index: &str) -> Result<i32> {
+        let attribute = format!("temp{index}_input");
+        parse_attribute(chip, &attribute, self.read_attribute(chip, &attribute)?)
+    }
+
+    fn read_pwm(&self, chip: &Path, index: &str) -> Result<u8> {
+        let attribute = format!("pwm{index}");
+        parse_attribute(chip, &attribute, self.read_attribute(chip, &attribute)?)
+    }
+
+    fn write_pwm(&self, chip: &Path, index: &str, duty: u8) -> Result<()> {
+        self.write_attribute(chip, &format!("pwm{index}"), &duty.to_string())
+    }
+
+    fn read_pwm_enable(&self, chip: &Path, index: &str) -> Result<u8> {
+        let attribute = format!("pwm{index}_enable");
+        parse_attribute(chip, &attribute, self.read_attribute(chip, &attribute)?)
+    }
+
+    fn write_pwm_enable(&self, chip: &Path, index: &str, mode: u8) -> Result<()> {
+        self.write_attribute(chip, &format!("pwm{index}_enable"), &mode.to_string())
+    }
+}
+
+pub struct SysfsBackend {
+    root: PathBuf,
+}
+
+impl SysfsBackend {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+}
+
+impl HwmonBackend for SysfsBackend {
+    fn enumerate_chips(&self) -> Result<Vec<ChipInfo>> {
+        let mut list = Vec::new();
+
+        for entry in fs::read_dir(&self.root).map_err(|e| Error::from_io(&self.root, e))? {
+            let entry = entry.map_err(|e| Error::from_io(&self.root, e))?;
+            let path = entry.path();
+
+            if !path.file_name()
+                .and_then(|f| f.to_str())
+                .map(|s| s.starts_with("hwmon"))
+                .unwrap_or(false)
+            {
+                continue;
+            }
+
+            if let Ok(name) = path.join("name").read_trimmed(){
+                list.push(ChipInfo { path, name })
+            }
+        }
+
+        list.sort_by(|a, b| a.path.cmp(&b.path));
+        return Ok(list);
+    }
+
+    fn list_attributes(&self, chip: &Path) -> Result<Vec<String>> {
+        let mut list = Vec::new();
+
+        for entry in fs::read_dir(chip).map_err(|e| Error::from_io(chip, e))?.flatten() {
+            let path = entry.path();
+            if !fs::metadata(&path).map(|m| m.is_file()).unwrap_or(false) {
+                continue;
+            }
+
+            if let Some(name) = path.file_name().and_then(|s| s.to_str()) {
+                list.push(name.to_string());
+            }
+        }
+
+        return Ok(list);
+    }
+
+    fn read_attribute(&self, chip: &Path, attribute: &str) -> Result<String> {
+        let path = chip.join(attribute);
+        path.read_trimmed().map_err(|e| Error::from_io(&path, e))
+    }
+
+    fn write_attribute(&self, chip: &Path, attribute: &str, value: &str) -> Result<()> {
+        let path = chip.join(attribute);
+        fs::write(&path, value).map_err(|e| Error::from_io(&path, e))
+    }
+
+    fn device_info(&self, chip: &Path) -> Option<DeviceInfo> {
+        let device = fs::canonicalize(chip.join("device")).ok()?;
+        let link_name = |link: &str| fs::read_link(device.join(link)).ok().and_then(|p| p.file_name().map(|f| f.to_string_lossy().into_owned()));
+
+        let path = device.components()
+            .skip_while(|c| c.as_os_str() != "devices")
+            .collect::<PathBuf>();
+
+        Some(DeviceInfo {
+            bus: link_name("subsystem"),
+            address: device.file_name()?.to_string_lossy().into_owned(),
+            driver: link_name("driver"),
+            path,
+        })
+    }
+}
+
+impl DeviceInfo {
+    // e.g. "nct6775:nct6775@platform:nct6775.656" or "k10temp:k10temp@pci:0000:00:18.3"
+    pub fn chip_id(device: Option<&DeviceInfo>, name: &str) -> String {
+        let device = match device {
+            Some(d) => d,
+            None => return name.to_string(),
+        };
+
+        let driver = device.driver.as_ref().map(|d| format!(":{d}")).unwrap_or_default();
+        let bus = device.bus.as_ref().map(|b| format!("{b}:")).unwrap_or_default();
+        return format!("{name}{driver}@{bus}{}", device.address);
+    }
+}
+
+fn parse_attribute<T: std::str::FromStr>(chip: &Path, attribute: &str, raw: String) -> Result<T> {
+    raw.parse().map_err(|_| Error::Parse { path: chip.join(attribute), raw })
+}
+
+#[cfg(test)]
+pub use mock::MockBackend;
+
+#[cfg(test)]
+mod mock {
+    use std::{collections::BTreeMap, path::{Path, PathBuf}, sync::Mutex};
+
+    use super::{ChipInfo, DeviceInfo, HwmonBackend};
+    use crate::error::{Error, Result};
+
+    // In-memory chips keyed by path, each a map of attribute name to contents.
+    #[derive(Default)]
+    pub struct MockBackend {
+        chips: Mutex<BTreeMap<PathBuf, BTreeMap<String, String>>>,
+        devices: Mutex<BTreeMap<PathBuf, DeviceInfo>>,
+    }
+
+    impl MockBackend {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn with_chip(self, path: &str, name: &str) -> Self {
+            self.set(Path::new(path), "name", name);
+            return self;
+        }
+
+        pub fn with_device(self, chip: &str, bus: &str, address: &str, driver: &str) -> Self {
+            let device = DeviceInfo {
+                bus: Some(bus.to_string()),
+                address: address.to_string(),
+                driver: Some(driver.to_string()),
+                path: PathBuf::from(format!("devices/{bus}/{address}")),
+            };
+            self.devices.lock().unwrap().insert(PathBuf::from(chip), device);
+            return self;
+        }
+
+        pub fn with_attribute(self, chip: &str, attribute: &str, value: &str) -> Self {
+            self.set(Path::new(chip), attribute, value);
+            return self;
+        }
+
+        pub fn set(&self, chip: &Path, attribute: &str, value: &str) {
+            let mut chips = self.chips.lock().unwrap();
+            chips.entry(chip.to_path_buf()).or_default().insert(attribute.to_string(), value.to_string());
+        }
+
+        pub fn get(&self, chip: &Path, attribute: &str) -> Option<String> {
+            let chips = self.chips.lock().unwrap();
+            chips.get(chip).and_then(|c| c.get(attribute)).cloned()
+        }
+    }
+
+    impl HwmonBackend for MockBackend {
+        fn enumerate_chips(&self) -> Result<Vec<ChipInfo>> {
+            let chips = self.chips.lock().unwrap();
+            let list = chips.iter()
+                .filter_map(|(path, attrs)| attrs.get("name").map(|name| ChipInfo { path: path.clone(), name: name.clone() }))
+                .collect();
+
+            return Ok(list);
+        }
+
+        fn list_attributes(&self, chip: &Path) -> Result<Vec<String>> {
+            let chips = self.chips.lock().unwrap();
+            match chips.get(chip) {
+                Some(attrs) => Ok(attrs.keys().cloned().collect()),
+                None => Err(Error::NotFound { path: chip.to_path_buf() }),
+            }
+        }
+
+        fn read_attribute(&self, chip: &Path, attribute: &str) -> Result<String> {
+            self.get(chip, attribute).ok_or_else(|| Error::NotFound { path: chip.join(attribute) })
+        }
+
+        fn write_attribute(&self, chip: &Path, attribute: &str, value: &str) -> Result<()> {
+            if self.get(chip, attribute).is_none() {
+                return Err(Error::NotFound { path: chip.join(attribute) });
+            }
+
+            self.set(chip, attribute, value.trim());
+            return Ok(());
+        }
+
+        fn device_info(&self, chip: &Path) -> Option<DeviceInfo> {
+            self.devices.lock().unwrap().get(chip).cloned()
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::{thread, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{hwmon::{hwmon::Hwmon, pwm::Pwm}, units::{Duty, Rpm}};
+
+const DEFAULT_STEP: u8 = 15;
+const DEFAULT_SETTLE: Duration = Duration::from_secs(3);
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct CalibrationPoint {
+    pub duty: Duty,
+    pub rpm: Rpm,
+}
+
+// Measured behaviour of a fan on its paired pwm, saved with the pairing.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FanCalibration {
+    // Highest duty the fan stops at while slowing down, 0 if it never stopped
+    pub stall_duty: Duty,
+    // Lowest duty that spins the fan up from a standstill
+    pub start_duty: Duty,
+    pub max_rpm: Rpm,
+    pub table: Vec<CalibrationPoint>,
+}
+
+// Steps a pwm from full speed down to 0 and back up until every stopped fan restarts.
+#[derive(Clone, Debug)]
+pub struct Sweep {
+    pub step: u8,
+    pub settle: Duration,
+    pub samples: usize,
+}
+
+impl Default for Sweep {
+    fn default() -> Self {
+        Self { step: DEFAULT_STEP, settle: DEFAULT_SETTLE, samples: 3 }
+    }
+}
+
+impl Sweep {
+    pub fn duration(&self) -> Duration {
+        let steps = 255 / u32::from(self.step.max(1)) + 1;
+        (self.settle + SAMPLE_INTERVAL * self.samples as u32) * steps * 2
+    }
+
+    // Calibrates every fan paired with `pwm`, returned by position in `Hwmon::fans`.
+    pub fn run(&self, hwmon: &Hwmon, pwm: &Pwm) -> Vec<(usize, FanCalibration)> {
+        let fans: Vec<usize> = hwmon.fans.iter()
+            .enumerate()
+            .filter(|(_, f)| f.paired_pwm.as_ref().is_some_and(|p| p.is_same_channel(pwm)))
+            .map(|(i, _)| i)
+            .collect();
+        if fans.is_empty() {
+            return Vec::new();
+        }
+
+        let original = pwm.read_duty().ok();
+        let step = usize::from(self.step.max(1));
+
+        let mut down: Vec<(u8, Vec<Rpm>)> = Vec::new();
+        for duty in (0..=255u8).rev().step_by(step).chain((255 % step != 0).then_some(0)) {
+            down.push((duty, self.measure(hwmon, pwm, &fans, duty)));
+        }
+
+        // Spin back up from standstill until every fan that stopped is running again
+        let mut up: Vec<(u8, Vec<Rpm>)> = Vec::new();
+        let stopped = |rpms: &[Rpm]| rpms.contains(&Rpm::default());
+        if down.last().is_some_and(|(_, rpms)| stopped(rpms)) {
+            for duty in (0..=255u8).step_by(step).skip(1).chain([255]) {
+                let rpms = self.measure(hwmon, pwm, &fans, duty);
+                let done = !stopped(&rpms);
+                up.push((duty, rpms));
+                if done {
+                    break;
+                }
+            }
+        }
+
+        if let Some(duty) = original && let Err(e) = pwm.set_duty(duty) {
+            log::warn!("Unable to restore {} to {duty}: {e}", pwm.name);
+        }
+
+        return fans.iter()
+            .enumerate()
+            .map(|(column, fan)| {
+                let down: Vec<CalibrationPoint> = down.iter().map(|(duty, rpms)| CalibrationPoint { duty: Duty::new(*duty), rpm: rpms[column] }).collect();
+                let up: Vec<CalibrationPoint> = up.iter().map(|(duty, rpms)| CalibrationPoint { duty: Duty::new(*duty), rpm: rpms[column] }).collect();
+                (*fan, FanCalibration::from_sweep(&down, &up))
+            })
+            .collect();
+    }
+
+    // Averages the readings that succeeded, a fan that never answered counts as stopped.
+    fn measure(&self, hwmon: &Hwmon, pwm: &Pwm, fans: &[usize], duty: u8) -> Vec<Rpm> {
+        if let Err(e) = pwm.set_duty(Duty::new(duty)) {
+            log::warn!("Unable to set {} to {duty}: {e}", pwm.name);
+        }
+        thread::sleep(self.settle);
+
+        let mut totals = vec![(0, 0); fans.len()];
+        for _ in 0..self.samples.max(1) {
+            for ((total, count), fan) in totals.iter_mut().zip(fans.iter()) {
+                if let Ok(rpm) = hwmon.fans[*fan].get_speed() {
+                    *total += rpm.get();
+                    *count += 1;
+                }
+            }
+            thread::sleep(SAMPLE_INTERVAL);
+        }
+
+        return totals.into_iter().map(|(total, count)| Rpm::new(total.checked_div(count).unwrap_or(0))).collect();
+    }
+}
+
+impl FanCalibration {
+    // `down` runs from full speed to 0, `up` from just above 0 until the fan restarted.
+    pub fn from_sweep(down: &[CalibrationPoint], up: &[CalibrationPoint]) -> Self {
+        let stopped = Rpm::default();
+        let stall_duty = down.iter().find(|p| p.rpm == stopped).map(|p| p.duty).unwrap_or(Duty::OFF);
+        let start_duty = if stall_duty == Duty::OFF { Duty::OFF } else { up.iter().find(|p| p.rpm > stopped).map(|p| p.duty).unwrap_or(Duty::MAX) };
+        let max_rpm = down.iter().map(|p| p.rpm).max().unwrap_or_default();
+
+        let mut table = down.to_vec();
+        table.sort_by_key(|p| p.duty);
+
+        Self { stall_duty, start_duty, max_rpm, table }
+    }
+
+    // Lowest duty that keeps the fan running, whether it is spinning already or not.
+    pub fn min_duty(&self) -> Duty {
+        if self.stall_duty == Duty::OFF {
+            return Duty::OFF;
+        }
+
+        return self.start_duty.max(Duty::new(self.stall_duty.raw().saturating_add(1)));
+    }
+
+    // Interpolated from the table, None when the fan was never measured.
+    pub fn expected_rpm(&self, duty: Duty) -> Option<Rpm> {
+        let below = self.table.iter().rev().find(|p| p.duty <= duty);
+        let above = self.table.iter().find(|p| p.duty >= duty);
+
+        match (below, above) {
+            (Some(low), Some(high)) if high.duty > low.duty => {
+                let ratio = f32::from(duty.raw() - low.duty.raw()) / f32::from(high.duty.raw() - low.duty.raw());
+                let rpm = low.rpm.get() as f32 + ratio * (high.rpm.get() as f32 - low.rpm.get() as f32);
+                Some(Rpm::new(rpm.round().max(0.0) as u32))
+            }
+            (Some(p), _) | (None, Some(p)) => Some(p.rpm),
+            (None, None) => None,
+        }
+    }
+
+    pub fn min_rpm(&self) -> Rpm {
+        self.table.iter().map(|p| p.rpm).filter(|rpm| *rpm > Rpm::default()).min().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(points: &[(u8, u32)]) -> Vec<CalibrationPoint> {
+        points.iter().map(|(duty, rpm)| CalibrationPoint { duty: Duty::new(*duty), rpm: Rpm::new(*rpm) }).collect()
+    }
+
+    #[test]
+    fn finds_stall_start_and_max_from_a_sweep() {
+        let down = points(&[(255, 1800), (192, 1400), (128, 900), (64, 450), (32, 0), (0, 0)]);
+        let up = points(&[(32, 0), (64, 0), (96, 700)]);
+        let calibration = FanCalibration::from_sweep(&down, &up);
+
+        assert_eq!((calibration.stall_duty, calibration.start_duty, calibration.max_rpm), (Duty::new(32), Duty::new(96), Rpm::new(1800)));
+        assert_eq!(calibration.min_duty(), Duty::new(96));
+        assert_eq!(calibration.min_rpm(), Rpm::new(450));
+        assert_eq!(calibration.table.first(), Some(&CalibrationPoint { duty: Duty::OFF, rpm: Rpm::new(0) }));
+        assert_eq!(calibration.expected_rpm(Duty::new(160)), Some(Rpm::new(1150)));
+        assert_eq!(calibration.expected_rpm(Duty::MAX), Some(Rpm::new(1800)));
+
+        let never_stops = FanCalibration::from_sweep(&points(&[(255, 1200), (0, 300)]), &[]);
+        assert_eq!(never_stops.min_duty(), Duty::OFF);
+    }
+}
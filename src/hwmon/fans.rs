@@ -1,22 +1,24 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc};
 
-use crate::{hwmon::pwm::Pwm, path_helpers};
+use crate::{error::{Error, Result}, hwmon::{backend::HwmonBackend, calibration::FanCalibration, pwm::Pwm}, units::Rpm};
 
 #[derive(Clone)]
 pub struct Fan {
+    backend: Arc<dyn HwmonBackend>,
     file_path: PathBuf,
     pub index: i32,
     pub label: String,
-    pub min_speed_rpm: i32,
-    pub max_speed_rpm: i32,
-    pub current_speed: i32,
+    pub min_speed_rpm: Rpm,
+    pub max_speed_rpm: Rpm,
+    pub current_speed: Rpm,
     pub paired_pwm: Option<Pwm>,
+    pub pair_confidence: Option<f32>,
+    pub calibration: Option<FanCalibration>,
 }
 
 impl Fan {
-    pub fn new(path: PathBuf) -> Self {
-        let p = path.clone();
-        Self {file_path: path, index: 0, label: "".into(), max_speed_rpm: 0, min_speed_rpm: 0, current_speed: get_speed(p), paired_pwm: None }
+    pub fn new(backend: Arc<dyn HwmonBackend>, path: PathBuf) -> Self {
+        Self {backend, file_path: path, index: 0, label: "".into(), max_speed_rpm: Rpm::default(), min_speed_rpm: Rpm::default(), current_speed: Rpm::default(), paired_pwm: None, pair_confidence: None, calibration: None }
     }
 
     pub fn with_label(mut self, s: String) -> Self {
@@ -24,18 +26,18 @@ impl Fan {
         return self;
     }
 
-    pub fn with_index(mut self, i: String) -> Self {
-        self.index = i.parse::<i32>().unwrap_or(0);
+    pub fn with_index(mut self, index: i32) -> Self {
+        self.index = index;
         return self;
     }
 
-    pub fn with_rpm(mut self, min: i32, max: i32) -> Self {
+    pub fn with_rpm(mut self, min: Rpm, max: Rpm) -> Self {
         self.min_speed_rpm = min;
         self.max_speed_rpm = max;
         return self;
     }
 
-    pub fn with_current_speed(mut self, speed: i32) -> Self {
+    pub fn with_current_speed(mut self, speed: Rpm) -> Self {
         self.current_speed = speed;
         return self;
     }
@@ -45,27 +47,61 @@ impl Fan {
     //     //TODO: write to file.
     // }
 
-    pub fn get_speed(&self) -> i32{
-        get_speed(self.get_input_path())
+    pub fn get_speed(&self) -> Result<Rpm> {
+        self.backend.read_fan_rpm(&self.file_path, &self.index.to_string()).map(Rpm::new)
     }
 
     pub fn get_formatted_speed(&self) -> String {
-        return format!("{} RPM", &self.get_speed());
+        match self.get_speed() {
+            Ok(rpm) => rpm.to_string(),
+            Err(e) => e.reason().to_string(),
+        }
     }
 
-    pub fn get_formatted_cached_speed(&self) -> String {
-        return format!("{} RPM", &self.current_speed);
+    // Pairs with a new pwm, anything measured against the old one no longer applies.
+    pub fn pair_with(&mut self, pwm: Pwm, confidence: Option<f32>) {
+        self.paired_pwm = Some(pwm);
+        self.pair_confidence = confidence;
+        self.calibration = None;
     }
 
-    pub fn update_speed(&mut self){
-        self.current_speed = path_helpers::read_from_path(&self.get_input_path()).parse::<i32>().unwrap_or(0);
+    // Forgets the pwm and everything measured against it, e.g. before pairing the chip again.
+    pub fn unpair(&mut self) {
+        self.paired_pwm = None;
+        self.pair_confidence = None;
+        self.calibration = None;
     }
 
-    fn get_input_path(&self) -> PathBuf {
-        self.file_path.join(format!("fan{}_input", self.index))
+    pub fn set_calibration(&mut self, calibration: FanCalibration) {
+        self.min_speed_rpm = calibration.min_rpm();
+        self.max_speed_rpm = calibration.max_rpm;
+        self.calibration = Some(calibration);
+    }
+
+    // fanN_alarm and fanN_fault, missing attributes count as not set.
+    pub fn has_alarm(&self) -> bool {
+        self.read_flag("alarm")
+    }
+
+    pub fn has_fault(&self) -> bool {
+        self.read_flag("fault")
+    }
+
+    fn read_flag(&self, flag: &str) -> bool {
+        match self.backend.read_attribute(&self.file_path, &format!("fan{}_{flag}", self.index)) {
+            Ok(value) => value == "1",
+            Err(Error::NotFound { .. }) => false,
+            Err(e) => {
+                log::debug!("{e}");
+                false
+            }
+        }
     }
-}
 
-fn get_speed(path: PathBuf) -> i32 {
-    path_helpers::read_from_path(&path).parse::<i32>().unwrap_or(0)
+    // Keeps the last good reading when the fan can't be read.
+    pub fn update_speed(&mut self) {
+        if let Ok(speed) = self.get_speed() {
+            self.current_speed = speed;
+        }
+    }
 }
\ No newline at end of file
@@ -1,10 +1,15 @@
 use core::fmt;
-use std::{fmt::{Display, Formatter}, fs, io::Write, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread, time::Duration};
+use std::{fmt::{Display, Formatter}, path::{Path, PathBuf}, sync::Arc};
 
-use crate::{hwmon::{fans::Fan, pwm::Pwm, temp::Temp}, path_helpers::{self, ReadTrimmed}, terminal_utils};
+use crate::{error::Result, units::{Duty, Rpm}, hwmon::{backend::{DeviceInfo, HwmonBackend}, fans::Fan, pairing::PairingMatch, pwm::Pwm, temp::Temp}};
 
+#[derive(Clone)]
 pub struct Hwmon {
+    backend: Arc<dyn HwmonBackend>,
     path: PathBuf,
+    // Stable across boots, unlike the hwmonN directory
+    pub id: String,
+    pub device: Option<DeviceInfo>,
     pub name: String,
     pub fans: Vec<Fan>,
     pub temps: Vec<Temp>,
@@ -12,235 +17,252 @@ pub struct Hwmon {
 }
 
 impl Hwmon {
-    pub fn new(path: PathBuf, name: String) -> Self {
-        Self {path: path, name: name, fans: Vec::new(), temps: Vec::new(), pwms: Vec::new()}
+    pub fn new(backend: Arc<dyn HwmonBackend>, path: PathBuf, name: String) -> Self {
+        let device = backend.device_info(&path);
+        let id = DeviceInfo::chip_id(device.as_ref(), &name);
+        Self {backend, path, id, device, name, fans: Vec::new(), temps: Vec::new(), pwms: Vec::new()}
     }
 
-    pub fn initialize(&mut self) {
-        self.initialize_fans();
-        self.initialize_pwms();
-        self.initialize_temps();
+    // Fails when the chip's attributes can't be listed, e.g. because the device went away.
+    pub fn initialize(&mut self) -> Result<()> {
+        let attributes = self.backend.list_attributes(&self.path)?;
+        self.initialize_fans(&attributes);
+        self.initialize_pwms(&attributes);
+        self.initialize_temps(&attributes);
+        return Ok(());
     }
 
     pub fn path(&self) -> &Path {
         self.path.as_path()
     }
 
-    pub fn set_all_pwm(&self, pwm_value: i32) {
-        for pwm in self.pwms.iter() {
-            pwm.write_speed(pwm_value);
-        }
+    // The hwmonN directory name, unlike `name` this is unique on a running system.
+    pub fn dir_name(&self) -> &str {
+        self.path.file_name().and_then(|s| s.to_str()).unwrap_or("")
     }
 
-    pub fn try_pair_fans_to_pwm(&mut self) {
-        for fan in self.fans.iter_mut() {
-            fan.update_speed();
-        }
+    pub fn read_raw(&self, attribute: &str) -> Result<String> {
+        self.backend.read_attribute(&self.path, attribute)
+    }
 
-        for pwm in self.pwms.iter() {
-            terminal_utils::clear_terminal();
-            println!("Setting {} to max speed...", pwm.name);
-            pwm.write_speed(255);
-
-  
-            let fans_arc = Arc::new(self.fans.clone());
-            let header = Arc::new(format!("Setting {} to max speed...", pwm.name).to_string());
-            let stop_flag = terminal_utils::spawn_live_fan_speed_thread(fans_arc, header);
-
-            thread::sleep(Duration::from_secs(5));
-
-            let mut diff_requirement = 400;
-
-            loop {
-                let mut possible_fans = self.fans
-                .iter()
-                .enumerate()
-                .filter(|(_, fan)| fan.get_speed().abs_diff(fan.current_speed) > diff_requirement);
-
-               let unique_index = match (possible_fans.next(), possible_fans.next()) {
-                (Some((i, _)), None) => Some(i),
-                _ => None
-               };
-
-               if let Some(i) = unique_index {
-                    let fan: &mut Fan = &mut self.fans[i];
-                    fan.paired_pwm = Some(pwm.clone());
-                    stop_flag.store(true, Ordering::Relaxed);
-
-                    println!("{} matched to fan {}", pwm.name, fan.label);
-                    pwm.write_speed(100);
-                    terminal_utils::wait_for_user_input();
-                    break;
-               } else if diff_requirement > 1000 {
-                    stop_flag.store(true, Ordering::Relaxed);
-                    println!("Unable to match {}", pwm.name);
-                    
-                    pwm.write_speed(100);
-                    terminal_utils::wait_for_user_input();
-                    break;
-               } else {
-                    diff_requirement += 100;
-                    std::io::stdout().flush().unwrap();
-               }
+    pub fn has_pairings(&self) -> bool {
+        self.fans.iter().any(|f| f.paired_pwm.is_some())
+    }
+
+    pub fn paired_pwms(&self) -> Vec<Pwm> {
+        let mut pwms: Vec<Pwm> = Vec::new();
+
+        for pwm in self.fans.iter().filter_map(|f| f.paired_pwm.as_ref()) {
+            if !pwms.iter().any(|p| p.index == pwm.index) {
+                pwms.push(pwm.clone());
             }
         }
+
+        return pwms;
     }
 
-    pub fn manual_pair_fan_to_pwm(&mut self) {
+    // Call before pairing again, a fan the new run doesn't pair must not keep its old pwm.
+    pub fn clear_pairings(&mut self) {
         for fan in self.fans.iter_mut() {
-            fan.update_speed();
+            fan.unpair();
         }
+    }
 
+    pub fn set_all_pwm(&self, pwm_value: Duty) {
         for pwm in self.pwms.iter() {
-            terminal_utils::clear_terminal();
-            println!();
-            pwm.write_speed(255);
-
-            let fans_arc = Arc::new(self.fans.clone());
-            let header = Arc::new(format!("Setting {} to max speed...", pwm.name).to_string());
-            let (stop_flag, index_receiver) = terminal_utils::spawn_live_fan_speed_thread_with_selection(fans_arc, header);
-
-            if let Ok(i) = index_receiver.recv() {
-                if let Some(fan) = &mut self.fans.iter_mut().find(|f| f.index == i32::try_from(i).expect("Value too large for i32")) {
-                    fan.paired_pwm = Some(pwm.clone());
-                    pwm.write_speed(100);
-                    stop_flag.store(true, Ordering::Relaxed);
-
-                    println!("Paired {} to {}", pwm.name, fan.label);
-                    terminal_utils::wait_for_user_input();
-                    continue;
-                } else {
-                    println!("Error pairing fan...");
-                    terminal_utils::wait_for_user_input();
-                    continue;
-                };
-            } else {
-                println!("{} not paried to any fan", pwm.name);
-                pwm.write_speed(100);
-                terminal_utils::wait_for_user_input();
-                continue;
+            if let Err(e) = pwm.set_duty(pwm_value) {
+                log::error!("Unable to set {}: {e}", pwm.name);
             }
         }
     }
 
-    pub fn initialize_fans(&mut self) {
-        let base_path = self.path();
-
-        if let Ok(directory) = fs::read_dir(base_path){
-            let mut list = Vec::new();
-            for dir_entry in directory.flatten() {
-                let path = dir_entry.path();
-                if !is_file(&path) {continue;}
+    // Pairs every confident match, asking `confirm` about the rest. Returns the number of fans paired.
+    pub fn apply_matches<F>(&mut self, matches: &[PairingMatch], mut confirm: F) -> usize
+    where
+        F: FnMut(&Fan, &Pwm, f32) -> bool,
+    {
+        let mut paired = 0;
 
-                let name = basename(&path);
+        for m in matches.iter() {
+            let pwm = self.pwms[m.pwm].clone();
+            let fan = &mut self.fans[m.fan];
 
-                if let Some(index) = extract_index(name, "fan", "_input") {
-                    let current_speed = &path.read_trimmed().unwrap_or_else(|_| "?".into());
-                    let label = path_helpers::read_from_file(base_path, format!("fan{}_label", index));
-                    let max = path_helpers::read_from_file(base_path, format!("fan{}_max", index));
-                    let min = path_helpers::read_from_file(base_path, format!("fan{}_min", index));
-
-                    list.push(Fan::new(base_path.to_path_buf())
-                                    .with_current_speed(current_speed.parse::<i32>().unwrap_or(0))
-                                    .with_index(index)
-                                    .with_label(label)
-                                    .with_rpm(min.parse().unwrap_or(0), max.parse().unwrap_or(0)));
-                }
+            if !m.is_confident() && !confirm(fan, &pwm, m.confidence) {
+                continue;
             }
 
-            self.fans = list;
+            log::info!("{} matched to fan {} ({:.0}% confidence)", pwm.name, fan.label, m.confidence * 100.0);
+            fan.pair_with(pwm, Some(m.confidence));
+            paired += 1;
         }
-    }
 
-    pub fn initialize_temps(&mut self) {
-        let base_path = self.path();
+        return paired;
+    }
 
-        if let Ok(directory) = fs::read_dir(base_path) {
-            let mut list = Vec::new();
-            for dir_entry in directory.flatten() {
-                let path = dir_entry.path();
-                if !is_file(&path) {continue;}
+    fn initialize_fans(&mut self, attributes: &[String]) {
+        let mut list = Vec::new();
+
+        for name in attributes {
+            if let Some(index) = extract_index(name, "fan", "_input") {
+                let number = match index.parse::<i32>() {
+                    Ok(n) => n,
+                    Err(_) => {
+                        log::warn!("{}: skipping {name}, not a valid fan index", self.id);
+                        continue;
+                    }
+                };
 
-                let name = basename(&path);
+                let fan = Fan::new(Arc::clone(&self.backend), self.path.clone())
+                                .with_index(number)
+                                .with_label(self.read_attribute(&format!("fan{}_label", index)))
+                                .with_rpm(self.read_rpm_limit(&format!("fan{}_min", index)), self.read_rpm_limit(&format!("fan{}_max", index)));
+
+                // A fan that can't be read right now is still listed, its reads will report why
+                let current_speed = match fan.get_speed() {
+                    Ok(rpm) => rpm,
+                    Err(e) => {
+                        log::warn!("{}: {e}", self.id);
+                        Rpm::default()
+                    }
+                };
 
-                if let Some(index) = extract_index(name, "temp", "_input") {
-                    let label = path_helpers::read_from_file(base_path, format!("temp{}_label", index));
-                    
-                    list.push(Temp::new(base_path.to_path_buf())
-                                    .with_index(index)
-                                    .with_label(label));
-                }
+                list.push(fan.with_current_speed(current_speed));
             }
-
-            self.temps = list;
         }
-    }
 
-    pub fn initialize_pwms(&mut self) {
-        let base_path = self.path();
-        
-        if let Ok(directory) = fs::read_dir(base_path) {
-            let mut list = Vec::new();
-            for dir_entry in directory.flatten() {
-                let path = dir_entry.path();
-                if !is_file(&path) {continue;}
+        self.fans = list;
+    }
 
-                let name = basename(&path);
-                if name.ends_with("_enable") {continue;}
+    fn initialize_temps(&mut self, attributes: &[String]) {
+        let mut list = Vec::new();
 
+        for name in attributes {
+            if let Some(index) = extract_index(name, "temp", "_input") {
+                let label = self.read_attribute(&format!("temp{}_label", index));
 
-                if let Some(index) = extract_index(name, "pwm", "") {
-                    list.push(Pwm::new(base_path.to_path_buf())
+                list.push(Temp::new(Arc::clone(&self.backend), self.path.clone())
                                 .with_index(index)
-                                .with_name(name.to_string()));
-                }
+                                .with_label(label));
             }
-
-            self.pwms = list;
         }
-    }
 
-    pub fn print_temps(&self) {
-       println!("-- temps --"); 
-       
-       for temp in self.temps.iter(){
-            println!("{}: {}", temp.label, temp.get_temp())
-       }
+        self.temps = list;
     }
 
-    pub fn print_fans(&self) {
-       println!("-- fans --"); 
-       
-       for fan in self.fans.iter(){
-            println!("{}: {}", fan.label, fan.get_speed())
-       }
+    fn initialize_pwms(&mut self, attributes: &[String]) {
+        let mut list = Vec::new();
+
+        for name in attributes {
+            if name.ends_with("_enable") {continue;}
+
+            if let Some(index) = extract_index(name, "pwm", "") {
+                list.push(Pwm::new(Arc::clone(&self.backend), self.path.clone())
+                            .with_index(index)
+                            .with_name(name.clone()));
+            }
+        }
+
+        self.pwms = list;
     }
 
-    pub fn print_pwms(&self) {
-        println!("-- pwms --");
+    // Labels are optional, a missing one is just empty.
+    fn read_attribute(&self, attribute: &str) -> String {
+        self.read_raw(attribute).unwrap_or_default()
+    }
 
-        for pwm in self.pwms.iter(){
-            println!("{}: {}", pwm.name, pwm.get_speed())
+    // fanN_min/fanN_max are optional too, 0 means no limit.
+    fn read_rpm_limit(&self, attribute: &str) -> Rpm {
+        match self.read_raw(attribute).map(|raw| raw.parse::<u32>()) {
+            Ok(Ok(rpm)) => Rpm::new(rpm),
+            Ok(Err(_)) => {
+                log::warn!("{}: {attribute} is not a number, ignoring it", self.id);
+                Rpm::default()
+            }
+            Err(_) => Rpm::default(),
         }
     }
 }
 
 impl Display for Hwmon {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", self.name, self.path.display())
+        write!(f, "{} {}", self.id, self.path.display())
     }
 }
 
-fn basename<'a>(p: &'a Path) -> &'a str {
-    p.file_name().and_then(|s| s.to_str()).unwrap_or("")
-}
-
-fn is_file(p: &Path) -> bool {
-    fs::metadata(p).map(|m| m.is_file()).unwrap_or(false)
-}
-
 fn extract_index(name: &str, prefix: &str, suffix: &str) -> Option<String> {
     if !name.starts_with(prefix) || !name.ends_with(suffix) { return None; }
     let mid = &name[prefix.len()..name.len()-suffix.len()];
     if mid.chars().all(|c| c.is_ascii_digit()) { Some(mid.to_string()) } else { None }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, fake_sysfs::FakeHwmonTree, fan_service::FanService, hwmon::{backend::SysfsBackend, calibration::FanCalibration, pairing::PairingMatch}, hwmon_service::HwmonService, units::Duty};
+
+    #[test]
+    fn initialize_reads_fans_temps_and_pwms() {
+        let tree = FakeHwmonTree::new();
+        let chip = tree.chip(0, "nct6775")
+            .with_fan(1, 1200).with_fan_label(1, "CPU Fan")
+            .with_fan(2, 800)
+            .with_file("fan2_min", "300")
+            .with_file("fan2_max", "1800")
+            .with_temp(1, 41500).with_temp_label(1, "SYSTIN")
+            .with_pwm(1, 128, 5)
+            .with_pwm(2, 255, 1);
+
+        let mut hwmon = Hwmon::new(Arc::new(SysfsBackend::new(tree.root().to_path_buf())), chip.path().to_path_buf(), "nct6775".into());
+        hwmon.initialize().unwrap();
+        hwmon.fans.sort_by_key(|f| f.index);
+        hwmon.pwms.sort_by(|a, b| a.index.cmp(&b.index));
+
+        assert_eq!(hwmon.fans.len(), 2);
+        assert_eq!(hwmon.fans[0].label, "CPU Fan");
+        assert_eq!(hwmon.fans[0].current_speed, Rpm::new(1200));
+        assert_eq!((hwmon.fans[1].min_speed_rpm, hwmon.fans[1].max_speed_rpm), (Rpm::new(300), Rpm::new(1800)));
+
+        assert_eq!(hwmon.temps.len(), 1);
+        assert_eq!(hwmon.temps[0].label, "SYSTIN");
+        assert_eq!(hwmon.temps[0].get_temp().unwrap().celsius(), 41.5);
+
+        let pwm_names: Vec<_> = hwmon.pwms.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(pwm_names, ["pwm1", "pwm2"]);
+    }
+
+    #[test]
+    fn pairing_again_replaces_the_saved_pairings_of_the_chip() {
+        let tree = FakeHwmonTree::new();
+        tree.chip(0, "nct6775").with_device("platform", "nct6775.656", "nct6775").with_fan(1, 800).with_fan(2, 900).with_pwm(1, 128, 1).with_pwm(2, 128, 1);
+        let discover = || {
+            let mut hwmon_service = HwmonService::new(tree.root().to_path_buf()).unwrap();
+            hwmon_service.initialize_hwmons();
+            for hwmon in hwmon_service.hwmons.iter_mut() {
+                hwmon.fans.sort_by_key(|f| f.index);
+                hwmon.pwms.sort_by(|a, b| a.index.cmp(&b.index));
+            }
+            hwmon_service
+        };
+
+        let mut service = FanService::new(discover(), Config::default());
+        for i in 0..2 {
+            let pwm = service.hwmons[0].pwms[i].clone();
+            service.hwmons[0].fans[i].pair_with(pwm, None);
+        }
+        let calibration = FanCalibration { stall_duty: Duty::new(40), start_duty: Duty::new(60), max_rpm: Rpm::new(2000), table: Vec::new() };
+        service.hwmons[0].fans[0].set_calibration(calibration);
+        service.store_pairings(0);
+        assert_eq!(service.config.pairings.len(), 2);
+
+        // The next run finds fan1 on pwm2 and fan2 on nothing, neither may keep what was saved before
+        let mut service = FanService::new(discover(), service.config);
+        assert!(service.hwmons[0].fans[0].calibration.is_some());
+        let hwmon = &mut service.hwmons[0];
+        hwmon.clear_pairings();
+        assert!(!hwmon.has_pairings() && hwmon.fans.iter().all(|f| f.calibration.is_none()));
+        assert_eq!(hwmon.apply_matches(&[PairingMatch { fan: 0, pwm: 1, confidence: 0.95, runner_up: 0.0 }], |_, _, _| false), 1);
+        service.store_pairings(0);
+
+        let pairings: Vec<_> = service.config.pairings.iter().map(|p| (p.fan_index, p.pwm_index.as_str(), p.calibration.is_some())).collect();
+        assert_eq!(pairings, [(1, "2", false)]);
+    }
+}
@@ -1,4 +1,8 @@
+pub mod backend;
 pub mod fans;
 pub mod temp;
 pub mod pwm;
+pub mod pwm_state;
+pub mod pairing;
+pub mod calibration;
 pub mod hwmon;
\ No newline at end of file
@@ -0,0 +1,181 @@
+use std::{thread, time::Duration};
+
+use crate::{hwmon::hwmon::Hwmon, units::Duty};
+
+const DEFAULT_DUTIES: [Duty; 7] = [Duty::new(40), Duty::new(100), Duty::new(160), Duty::MAX, Duty::new(160), Duty::new(100), Duty::new(40)];
+const DEFAULT_SETTLE: Duration = Duration::from_secs(3);
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+// Below this spread a fan counts as only partly responsive, however well it correlates.
+const FULL_RESPONSE_RPM: f32 = 150.0;
+const MIN_CONFIDENCE: f32 = 0.4;
+const ACCEPT_CONFIDENCE: f32 = 0.8;
+const ACCEPT_MARGIN: f32 = 0.3;
+
+// Duties a pwm is stepped through while every fan is sampled, low to high and back.
+#[derive(Clone, Debug)]
+pub struct Ramp {
+    pub duties: Vec<Duty>,
+    pub settle: Duration,
+    pub samples: usize,
+}
+
+// Averaged rpm of every fan (by position in `Hwmon::fans`) at each duty of a ramp.
+#[derive(Clone, Debug)]
+pub struct RampResponse {
+    pub pwm: usize,
+    pub duties: Vec<f32>,
+    pub rpms: Vec<Vec<f32>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PairingMatch {
+    pub fan: usize,
+    pub pwm: usize,
+    pub confidence: f32,
+    pub runner_up: f32,
+}
+
+impl Default for Ramp {
+    fn default() -> Self {
+        Self { duties: DEFAULT_DUTIES.to_vec(), settle: DEFAULT_SETTLE, samples: 3 }
+    }
+}
+
+impl Ramp {
+    pub fn duration(&self) -> Duration {
+        (self.settle + SAMPLE_INTERVAL * self.samples as u32) * self.duties.len() as u32
+    }
+
+    // Steps one pwm through the ramp and puts its duty back afterwards.
+    pub fn record(&self, hwmon: &Hwmon, pwm: usize) -> RampResponse {
+        let channel = &hwmon.pwms[pwm];
+        let original = channel.read_duty().ok();
+        let mut response = RampResponse { pwm, duties: Vec::new(), rpms: vec![Vec::new(); hwmon.fans.len()] };
+
+        for duty in self.duties.iter() {
+            if let Err(e) = channel.set_duty(*duty) {
+                log::warn!("Unable to set {} to {duty}: {e}", channel.name);
+            }
+            thread::sleep(self.settle);
+
+            let mut totals = vec![(0.0, 0); hwmon.fans.len()];
+            for _ in 0..self.samples.max(1) {
+                for ((total, count), fan) in totals.iter_mut().zip(hwmon.fans.iter()) {
+                    if let Ok(rpm) = fan.get_speed() {
+                        *total += rpm.get() as f32;
+                        *count += 1;
+                    }
+                }
+                thread::sleep(SAMPLE_INTERVAL);
+            }
+
+            response.duties.push(f32::from(duty.raw()));
+            for (rpms, (total, count)) in response.rpms.iter_mut().zip(totals) {
+                rpms.push(if count == 0 { 0.0 } else { total / count as f32 });
+            }
+        }
+
+        if let Some(duty) = original && let Err(e) = channel.set_duty(duty) {
+            log::warn!("Unable to restore {} to {duty}: {e}", channel.name);
+        }
+
+        return response;
+    }
+}
+
+impl PairingMatch {
+    // Confident matches are paired directly, the rest should be confirmed by hand.
+    pub fn is_confident(&self) -> bool {
+        self.confidence >= ACCEPT_CONFIDENCE && self.confidence - self.runner_up >= ACCEPT_MARGIN
+    }
+}
+
+// How strongly a fan follows the duty, from 0 (not at all) to 1.
+pub fn confidence(duties: &[f32], rpms: &[f32]) -> f32 {
+    let spread = rpms.iter().copied().fold(f32::NEG_INFINITY, f32::max) - rpms.iter().copied().fold(f32::INFINITY, f32::min);
+    if !spread.is_finite() {
+        return 0.0;
+    }
+
+    return correlation(duties, rpms).max(0.0) * (spread / FULL_RESPONSE_RPM).min(1.0);
+}
+
+// Pearson correlation, 0 when either series is flat.
+pub fn correlation(xs: &[f32], ys: &[f32]) -> f32 {
+    let n = xs.len().min(ys.len());
+    if n < 2 {
+        return 0.0;
+    }
+
+    let mean_x = xs[..n].iter().sum::<f32>() / n as f32;
+    let mean_y = ys[..n].iter().sum::<f32>() / n as f32;
+    let (mut covariance, mut var_x, mut var_y) = (0.0, 0.0, 0.0);
+
+    for (x, y) in xs[..n].iter().zip(ys[..n].iter()) {
+        covariance += (x - mean_x) * (y - mean_y);
+        var_x += (x - mean_x).powi(2);
+        var_y += (y - mean_y).powi(2);
+    }
+
+    if var_x == 0.0 || var_y == 0.0 {
+        return 0.0;
+    }
+
+    return covariance / (var_x * var_y).sqrt();
+}
+
+// Picks the best pwm for every fan, several fans may end up on the same pwm.
+pub fn match_fans(responses: &[RampResponse], fan_count: usize) -> Vec<PairingMatch> {
+    let mut matches = Vec::new();
+
+    for fan in 0..fan_count {
+        let mut scores: Vec<(usize, f32)> = responses.iter()
+            .map(|r| (r.pwm, r.rpms.get(fan).map(|rpms| confidence(&r.duties, rpms)).unwrap_or(0.0)))
+            .collect();
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        if let Some(&(pwm, best)) = scores.first() && best >= MIN_CONFIDENCE {
+            let runner_up = scores.get(1).map(|s| s.1).unwrap_or(0.0);
+            matches.push(PairingMatch { fan, pwm, confidence: best, runner_up });
+        }
+    }
+
+    return matches;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(pwm: usize, rpms: Vec<Vec<f32>>) -> RampResponse {
+        RampResponse { pwm, duties: DEFAULT_DUTIES.iter().map(|d| f32::from(d.raw())).collect(), rpms }
+    }
+
+    #[test]
+    fn matches_split_and_small_range_fans_but_not_drift() {
+        let follows = vec![400.0, 900.0, 1400.0, 2000.0, 1400.0, 900.0, 400.0];
+        let small_range = vec![800.0, 850.0, 900.0, 980.0, 900.0, 850.0, 800.0];
+        let drifting = vec![1000.0, 1050.0, 1100.0, 1150.0, 1200.0, 1250.0, 1300.0];
+        let flat = vec![1000.0; 7];
+
+        let responses = [
+            response(0, vec![follows.clone(), follows.clone(), flat.clone(), drifting.clone()]),
+            response(1, vec![flat.clone(), flat.clone(), small_range, drifting]),
+        ];
+        let matches = match_fans(&responses, 4);
+
+        let pairs: Vec<_> = matches.iter().map(|m| (m.fan, m.pwm)).collect();
+        assert_eq!(pairs, [(0, 0), (1, 0), (2, 1)]);
+        assert!(matches.iter().all(|m| m.is_confident()));
+        assert!(matches[0].confidence > 0.99);
+    }
+
+    #[test]
+    fn a_fan_following_two_pwms_needs_confirmation() {
+        let follows = vec![400.0, 900.0, 1400.0, 2000.0, 1400.0, 900.0, 400.0];
+        let matches = match_fans(&[response(0, vec![follows.clone()]), response(1, vec![follows])], 1);
+
+        assert_eq!(matches.len(), 1);
+        assert!(!matches[0].is_confident());
+    }
+}
@@ -1,17 +1,20 @@
-use std::{env, fs, io, path::PathBuf, process::{self, Command}};
+use std::{path::PathBuf, sync::Arc};
 
-use crate::path_helpers;
+use crate::{error::Result, hwmon::{backend::HwmonBackend, pwm_state}, units::Duty};
+
+pub const PWM_MODE_MANUAL: u8 = 1;
 
 #[derive(Clone)]
 pub struct Pwm {
+    backend: Arc<dyn HwmonBackend>,
     file_path: PathBuf,
     pub index: String,
     pub name: String,
 }
 
 impl Pwm {
-    pub fn new(path: PathBuf) -> Self {
-        Self { file_path: path, index: "".into(), name: "".into() }
+    pub fn new(backend: Arc<dyn HwmonBackend>, path: PathBuf) -> Self {
+        Self { backend, file_path: path, index: "".into(), name: "".into() }
     }
 
     pub fn with_index(mut self, index: String) -> Self {
@@ -24,32 +27,39 @@ impl Pwm {
         return self;
     }
 
-    pub fn write_speed(&self, new_speed: i32){
-        if new_speed > 255 {
-            return;
-        }
+    // Saves the original state and switches to manual mode before the first write.
+    // Without write access to the chip this fails with `Error::PermissionDenied`, nothing is retried as root.
+    pub fn set_duty(&self, duty: Duty) -> Result<()> {
+        pwm_state::write_duty(self, duty)
+    }
 
-        let write_res = fs::write(self.get_input_path(), format!("{new_speed}"));
+    pub fn read_duty(&self) -> Result<Duty> {
+        self.backend.read_pwm(&self.file_path, &self.index).map(Duty::new)
+    }
+
+    pub fn read_enable(&self) -> Result<u8> {
+        self.backend.read_pwm_enable(&self.file_path, &self.index)
+    }
 
-        match write_res {
-            Ok(_) => { return }
-            Err(e) if e.kind() == io::ErrorKind::PermissionDenied =>  {
-                    eprintln!("Need root to write {}. Re-running with sudo…", self.file_path.display());
-                    let exe = env::current_exe().unwrap_or_default();
-                    let args = env::args().skip(1);
-                    let status = Command::new("sudo").arg(exe).args(args).status().unwrap_or_default();
-                    process::exit(status.code().unwrap_or(1));
-            } 
-            Err(e) => eprintln!("{e} \n Error writing PWM value to file for {}", self.name)
-        }
+    pub(crate) fn is_same_channel(&self, other: &Pwm) -> bool {
+        self.file_path == other.file_path && self.index == other.index
     }
 
-    pub fn get_speed(&self) -> String {
-        let speed = path_helpers::read_from_path(&self.get_input_path());
-        return format!("{speed}");
+    // A reload opens the chips again through a new backend, only tests use this to tell their mocks apart.
+    #[cfg(test)]
+    pub(crate) fn shares_backend(&self, other: &Pwm) -> bool {
+        std::ptr::addr_eq(Arc::as_ptr(&self.backend), Arc::as_ptr(&other.backend))
     }
 
-    fn get_input_path(&self) -> PathBuf {
+    pub(crate) fn duty_path(&self) -> PathBuf {
         self.file_path.join(format!("pwm{}", self.index))
     }
-}
\ No newline at end of file
+
+    pub(crate) fn write_duty(&self, duty: Duty) -> Result<()> {
+        self.backend.write_pwm(&self.file_path, &self.index, duty.raw())
+    }
+
+    pub(crate) fn write_enable(&self, mode: u8) -> Result<()> {
+        self.backend.write_pwm_enable(&self.file_path, &self.index, mode)
+    }
+}
@@ -0,0 +1,150 @@
+use std::{panic, sync::{Mutex, MutexGuard, Once}, thread};
+
+use signal_hook::{consts::{SIGINT, SIGTERM}, iterator::Signals};
+
+use crate::{error::{Error, Result}, hwmon::pwm::{Pwm, PWM_MODE_MANUAL}, units::Duty};
+
+struct PwmState {
+    pwm: Pwm,
+    duty: Option<Duty>,
+    enable: Option<u8>,
+}
+
+struct SavedStates {
+    states: Vec<PwmState>,
+    // Set once a signal restored everything on the way out, a control loop thread must not write after that
+    exiting: bool,
+}
+
+// Original pwmN/pwmN_enable values, captured the first time each channel is written.
+static SAVED_STATES: Mutex<SavedStates> = Mutex::new(SavedStates { states: Vec::new(), exiting: false });
+static PANIC_HOOK: Once = Once::new();
+
+// Restores every PWM touched since it was created when dropped, and on panic.
+pub struct PwmStateGuard;
+
+impl PwmStateGuard {
+    pub fn new() -> Self {
+        PANIC_HOOK.call_once(|| {
+            let default_hook = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                restore_all();
+                default_hook(info);
+            }));
+        });
+
+        Self
+    }
+}
+
+impl Default for PwmStateGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PwmStateGuard {
+    fn drop(&mut self) {
+        restore_all();
+    }
+}
+
+// Restores and exits on Ctrl-C / SIGTERM, for flows that block on stdin.
+pub fn restore_on_signal() {
+    let mut signals = match Signals::new([SIGINT, SIGTERM]) {
+        Ok(s) => s,
+        Err(e) => {
+            log::warn!("Unable to register signal handlers, PWMs won't be restored on Ctrl-C: {e}");
+            return;
+        }
+    };
+
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            let mut saved = lock_states();
+            saved.exiting = true;
+            restore(&mut saved.states, |_| true);
+            std::process::exit(128 + signal);
+        }
+    });
+}
+
+// Writes under the lock, so a write can't land between a restore and the exit that follows it.
+// Once exiting the write is refused, the original value stays.
+pub(crate) fn write_duty(pwm: &Pwm, duty: Duty) -> Result<()> {
+    let mut saved = lock_states();
+    if saved.exiting {
+        return Err(Error::Exiting { path: pwm.duty_path() });
+    }
+
+    take_control(&mut saved.states, pwm);
+    return pwm.write_duty(duty);
+}
+
+fn take_control(states: &mut Vec<PwmState>, pwm: &Pwm) {
+    if states.iter().any(|s| s.pwm.is_same_channel(pwm)) {
+        return;
+    }
+
+    let state = PwmState { pwm: pwm.clone(), duty: pwm.read_duty().ok(), enable: pwm.read_enable().ok() };
+
+    if let Some(mode) = state.enable && mode != PWM_MODE_MANUAL && let Err(e) = pwm.write_enable(PWM_MODE_MANUAL) {
+        log::warn!("{}: unable to switch to manual mode: {e}", pwm.name);
+    }
+
+    states.push(state);
+}
+
+pub fn restore_all() {
+    restore(&mut lock_states().states, |_| true);
+}
+
+fn restore(states: &mut Vec<PwmState>, matches: impl Fn(&Pwm) -> bool) {
+    let (restoring, kept): (Vec<_>, Vec<_>) = states.drain(..).partition(|s| matches(&s.pwm));
+    *states = kept;
+
+    for state in restoring.into_iter().rev() {
+        if let Some(duty) = state.duty && let Err(e) = state.pwm.write_duty(duty) {
+            log::warn!("{}: unable to restore duty {duty}: {e}", state.pwm.name);
+        }
+
+        if let Some(mode) = state.enable && let Err(e) = state.pwm.write_enable(mode) {
+            log::warn!("{}: unable to restore enable mode {mode}: {e}", state.pwm.name);
+        }
+    }
+}
+
+fn lock_states() -> MutexGuard<'static, SavedStates> {
+    SAVED_STATES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use super::*;
+    use crate::hwmon::backend::MockBackend;
+
+    // The snapshots are shared by every test in the process, no other test may use this chip
+    const CHIP: &str = "/sys/class/hwmon/pwm_state_test";
+
+    #[test]
+    fn write_switches_to_manual_and_restore_puts_original_values_back() {
+        let backend = Arc::new(MockBackend::new()
+            .with_chip(CHIP, "nct6775")
+            .with_attribute(CHIP, "pwm1", "90")
+            .with_attribute(CHIP, "pwm1_enable", "5"));
+        let chip = Path::new(CHIP);
+        let pwm = Pwm::new(backend.clone(), chip.to_path_buf()).with_index("1".into()).with_name("pwm1".into());
+
+        pwm.set_duty(Duty::new(200)).unwrap();
+        pwm.set_duty(Duty::MAX).unwrap();
+        assert_eq!(backend.get(chip, "pwm1").as_deref(), Some("255"));
+        assert_eq!(backend.get(chip, "pwm1_enable").as_deref(), Some("1"));
+
+        // Only this test's channels, the other tests writing pwms in parallel keep their saved values
+        restore(&mut lock_states().states, |p| p.shares_backend(&pwm));
+        assert_eq!(backend.get(chip, "pwm1").as_deref(), Some("90"));
+        assert_eq!(backend.get(chip, "pwm1_enable").as_deref(), Some("5"));
+    }
+}
@@ -1,16 +1,18 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc};
 
-use crate::path_helpers;
+use crate::{error::Result, hwmon::backend::HwmonBackend, units::Temperature};
 
+#[derive(Clone)]
 pub struct Temp {
+    backend: Arc<dyn HwmonBackend>,
     file_path: PathBuf,
     pub index: String,
     pub label: String,
 }
 
 impl Temp {
-    pub fn new(path: PathBuf) -> Self {
-        Self {file_path: path, index: "".into(), label: "".into()}
+    pub fn new(backend: Arc<dyn HwmonBackend>, path: PathBuf) -> Self {
+        Self {backend, file_path: path, index: "".into(), label: "".into()}
     }
 
     pub fn with_label(mut self, label: String) -> Self {
@@ -23,24 +25,34 @@ impl Temp {
         return self;
     }
 
-    pub fn get_temp(&self) -> String {
-        let temp_milli_celcius = self.get_current_value().parse::<f32>().unwrap_or(0.0);
-        let temp_celcius = temp_milli_celcius / 1000.0;
+    pub fn get_temp(&self) -> Result<Temperature> {
+        self.backend.read_temp(&self.file_path, &self.index).map(Temperature::from_millicelsius)
+    }
+
+    pub fn get_formatted_temp(&self) -> String {
+        match self.get_temp() {
+            Ok(temp) => temp.to_string(),
+            Err(e) => e.reason().to_string(),
+        }
+    }
+
+    // Unique across chips, unlike the index and label.
+    pub(crate) fn input_path(&self) -> PathBuf {
+        self.file_path.join(format!("temp{}_input", self.index))
+    }
 
-        return format!("{temp_celcius} °C")
+    // The chip's own limit from tempN_crit, or tempN_max when there is no crit.
+    pub fn critical_temp(&self) -> Option<Temperature> {
+        ["crit", "max"].iter()
+            .filter_map(|limit| self.backend.read_attribute(&self.file_path, &format!("temp{}_{limit}", self.index)).ok())
+            .filter_map(|raw| raw.parse::<i32>().ok())
+            .find(|millicelsius| *millicelsius > 0)
+            .map(Temperature::from_millicelsius)
     }
 
     // pub fn edit_label(self, label: String){
     //     self.with_label(label);
     //     //TODO: write to file
     // }
-
-    fn get_current_value(&self) -> String {
-        path_helpers::read_from_path(self.get_input_path().as_path())
-    }
-
-    fn get_input_path(&self) -> PathBuf {
-        self.file_path.join(format!("temp{}_input", self.index))
-    }
 }
 
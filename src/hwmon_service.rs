@@ -1,61 +1,162 @@
-use std::{fs, io, path::Path};
-use crate::{hwmon::hwmon::Hwmon, path_helpers::ReadTrimmed};
+use std::{env, path::PathBuf, sync::Arc};
+use crate::{config::Config, error::Result, hwmon::{backend::{HwmonBackend, SysfsBackend}, hwmon::Hwmon}};
+
+pub const DEFAULT_SYSFS_ROOT: &str = "/sys/class/hwmon";
+pub const SYSFS_ROOT_ENV: &str = "FANCONTROL_SYSFS_ROOT";
 
 pub struct HwmonService {
     pub hwmons: Vec<Hwmon>
 }
 
 impl HwmonService {
-    pub fn new() -> Self {
-        Self {hwmons: get_hwmons()}
+    // Fails when the hwmon root itself can't be read, no chips is not an error.
+    pub fn new(root: PathBuf) -> Result<Self> {
+        Self::with_backend(Arc::new(SysfsBackend::new(root)))
+    }
+
+    pub fn with_backend(backend: Arc<dyn HwmonBackend>) -> Result<Self> {
+        Ok(Self {hwmons: get_hwmons(&backend)?})
     }
 
+    // Chips that can't be read are dropped with a warning so the rest stay usable.
     pub fn initialize_hwmons(&mut self) {
-        for hwmon in self.hwmons.iter_mut() {
-            hwmon.initialize();
-        }
+        self.hwmons.retain_mut(|hwmon| match hwmon.initialize() {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("Skipping {}: {e}", hwmon.id);
+                false
+            }
+        });
+    }
+
+    pub fn load_pairings(&mut self, config: &Config) -> usize {
+        config.apply_pairings(&mut self.hwmons)
+    }
+
+    // Finds a chip by its stable ID, by hwmonN directory name, or by name when only one chip has it.
+    pub fn find(&self, reference: &str) -> Option<usize> {
+        find_chip(&self.hwmons, reference)
     }
 }
 
+// Same lookup as `HwmonService::find`, for chips that have already left the service.
+pub fn find_chip(hwmons: &[Hwmon], reference: &str) -> Option<usize> {
+    if let Some(i) = hwmons.iter().position(|h| h.id == reference) {
+        return Some(i);
+    }
+    if let Some(i) = hwmons.iter().position(|h| h.dir_name() == reference) {
+        return Some(i);
+    }
 
-fn get_hwmons() -> Vec<Hwmon> {
-    let hwmons = match collect_hwmon() {
-        Ok(h) => h,
-        Err(e) => {
-            eprintln!("Error: {e}");
-            return Vec::new();
+    let mut by_name = hwmons.iter().enumerate().filter(|(_, h)| h.name == reference);
+    match (by_name.next(), by_name.next()) {
+        (Some((i, _)), None) => Some(i),
+        (Some(_), Some(_)) => {
+            log::warn!("More than one chip is named {reference}, use the chip ID from `list` instead");
+            None
         }
-    };
+        _ => None,
+    }
+}
 
-    if hwmons.is_empty() {
-            eprintln!("No hwmon");
-            return Vec::new();
-    } 
 
-    return hwmons
+pub fn default_root() -> PathBuf {
+    match env::var_os(SYSFS_ROOT_ENV) {
+        Some(root) if !root.is_empty() => PathBuf::from(root),
+        _ => PathBuf::from(DEFAULT_SYSFS_ROOT),
+    }
 }
 
-fn collect_hwmon() -> io::Result<Vec<Hwmon>> {
-    let root = Path::new("/sys/class/hwmon");
-    let mut list = Vec::new();
+fn get_hwmons(backend: &Arc<dyn HwmonBackend>) -> Result<Vec<Hwmon>> {
+    let chips = backend.enumerate_chips()?;
 
-    for entry in fs::read_dir(root)? {
-        let entry = entry?;
-        let path = entry.path();
+    if chips.is_empty() {
+            log::warn!("No hwmon");
+            return Ok(Vec::new());
+    } 
+
+    let hwmons: Vec<Hwmon> = chips.into_iter()
+        .map(|chip| Hwmon::new(Arc::clone(backend), chip.path, chip.name))
+        .collect();
 
-        if !path.file_name()
-            .and_then(|f| f.to_str())
-            .map(|s| s.starts_with("hwmon"))
-            .unwrap_or(false)
-        {
-            continue;
+    for (i, hwmon) in hwmons.iter().enumerate() {
+        if let Some(other) = hwmons[..i].iter().find(|h| h.id == hwmon.id) {
+            log::warn!("{} and {} share the chip ID {}, saved pairings and curves can't tell them apart", other.dir_name(), hwmon.dir_name(), hwmon.id);
         }
+    }
 
-        if let Ok(name) = path.join("name").read_trimmed(){
-            list.push(Hwmon::new(path, name))
+    return Ok(hwmons);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{error::Error, fake_sysfs::FakeHwmonTree, hwmon::backend::MockBackend, units::Rpm};
+
+    #[test]
+    fn discovers_named_hwmon_dirs_in_order() {
+        let tree = FakeHwmonTree::new();
+        tree.chip(1, "nct6775").with_fan(1, 900);
+        tree.chip(0, "k10temp").with_temp(1, 45000);
+        fs::create_dir(tree.root().join("hwmon2")).unwrap();
+        fs::create_dir(tree.root().join("not_a_chip")).unwrap();
+
+        let service = HwmonService::new(tree.root().to_path_buf()).unwrap();
+
+        let names: Vec<_> = service.hwmons.iter().map(|h| h.name.as_str()).collect();
+        assert_eq!(names, ["k10temp", "nct6775"]);
+    }
+
+    #[test]
+    fn discovers_chips_from_any_backend() {
+        let backend = Arc::new(MockBackend::new()
+            .with_chip("mock0", "it87")
+            .with_device("mock0", "platform", "it87.656", "it87")
+            .with_attribute("mock0", "fan1_input", "1100")
+            .with_attribute("mock0", "pwm1", "128"));
+
+        let mut service = HwmonService::with_backend(backend).unwrap();
+        service.initialize_hwmons();
+
+        let hwmon = &service.hwmons[0];
+        assert_eq!(hwmon.name, "it87");
+        assert_eq!(hwmon.id, "it87:it87@platform:it87.656");
+        assert_eq!(service.find("it87:it87@platform:it87.656"), Some(0));
+        assert_eq!(hwmon.fans[0].get_speed().unwrap(), Rpm::new(1100));
+        assert_eq!(hwmon.pwms[0].name, "pwm1");
+    }
+
+    #[test]
+    fn missing_root_is_reported_not_treated_as_no_chips() {
+        let tree = FakeHwmonTree::new();
+        let missing = tree.root().join("missing");
+
+        match HwmonService::new(missing.clone()) {
+            Err(Error::NotFound { path }) => assert_eq!(path, missing),
+            other => panic!("expected NotFound, got {:?}", other.map(|s| s.hwmons.len())),
         }
     }
 
-    list.sort_by(|a, b| a.path().cmp(&b.path()));
-    return Ok(list);
-}
\ No newline at end of file
+    #[test]
+    fn missing_and_garbled_sensors_are_errors_not_zero() {
+        let backend = Arc::new(MockBackend::new()
+            .with_chip("mock0", "it87")
+            .with_attribute("mock0", "fan1_input", "0")
+            .with_attribute("mock0", "fan2_input", "garbage"));
+
+        let mut service = HwmonService::with_backend(backend).unwrap();
+        service.initialize_hwmons();
+        let fans = &mut service.hwmons[0].fans;
+        fans.sort_by_key(|f| f.index);
+
+        assert_eq!(fans[0].get_speed().unwrap(), Rpm::new(0));
+        match fans[1].get_speed() {
+            Err(Error::Parse { path, raw }) => assert_eq!((path, raw.as_str()), (PathBuf::from("mock0/fan2_input"), "garbage")),
+            other => panic!("expected a parse error, got {other:?}"),
+        }
+        let missing = fans[0].clone().with_index(3);
+        assert!(matches!(missing.get_speed(), Err(Error::NotFound { .. })));
+    }
+}
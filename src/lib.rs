@@ -0,0 +1,33 @@
+//! Discovery, pairing and curve control of hwmon fans, without any terminal I/O.
+//! The `fancontrol` binary is the command line and interactive front end on top of this.
+
+#![allow(clippy::needless_return, clippy::module_inception)]
+
+pub mod alerts;
+pub mod config;
+pub mod control_loop;
+pub mod control_socket;
+pub mod curve;
+pub mod error;
+#[cfg(any(test, feature = "simulator"))]
+pub mod fake_sysfs;
+pub mod fan_monitor;
+pub mod fan_service;
+pub mod hwmon;
+pub mod hwmon_service;
+pub mod lm_sensors;
+pub mod metrics;
+pub mod profiles;
+#[cfg(any(test, feature = "simulator"))]
+pub mod simulator;
+pub mod snapshot;
+pub mod temp_source;
+pub mod units;
+pub mod watchdog;
+
+mod path_helpers;
+
+pub use error::{Error, Result};
+pub use hwmon::{fans::Fan, hwmon::Hwmon, pwm::Pwm, temp::Temp};
+pub use fan_service::FanService;
+pub use hwmon_service::HwmonService;
@@ -0,0 +1,350 @@
+use std::{collections::BTreeMap, fmt::{self, Display, Formatter}, path::PathBuf, time::Duration};
+
+use crate::{curve::{Curve, CurvePoint}, fan_service::FanService, hwmon::hwmon::Hwmon, temp_source::{Aggregate, SourceConfig, TempRef}, units::Duty};
+
+pub const DEFAULT_PATH: &str = "/etc/fancontrol";
+const DEFAULT_INTERVAL_SECS: u64 = 10;
+
+// The /etc/fancontrol file of the lm-sensors fancontrol script, as written by pwmconfig.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FancontrolFile {
+    pub interval_secs: u64,
+    // hwmonN to the device path relative to /sys and to the chip name, used to find chips after renumbering
+    pub devpath: BTreeMap<String, PathBuf>,
+    pub devname: BTreeMap<String, String>,
+    pub channels: Vec<Channel>,
+}
+
+// One pwm output with the temp and fans it follows, paths are relative to /sys/class/hwmon, e.g. "hwmon1/pwm2".
+#[derive(Clone, Debug, PartialEq)]
+pub struct Channel {
+    pub pwm: String,
+    pub temps: Vec<String>,
+    pub fans: Vec<String>,
+    pub min_temp: i32,
+    pub max_temp: i32,
+    pub min_start: Duty,
+    pub min_stop: Duty,
+    pub min_pwm: Duty,
+    pub max_pwm: Duty,
+}
+
+impl FancontrolFile {
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut settings: BTreeMap<&str, BTreeMap<String, String>> = BTreeMap::new();
+        let mut interval_secs = DEFAULT_INTERVAL_SECS;
+
+        for (number, line) in contents.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line.split_once('=').ok_or_else(|| format!("line {number}: expected KEY=value, got '{line}'"))?;
+            if key == "INTERVAL" {
+                interval_secs = value.trim().parse().map_err(|_| format!("line {number}: INTERVAL must be a number of seconds, got '{value}'"))?;
+                continue;
+            }
+
+            let mut entries = BTreeMap::new();
+            for entry in value.split_whitespace() {
+                let (target, setting) = entry.split_once('=').ok_or_else(|| format!("line {number}: expected path=value, got '{entry}'"))?;
+                entries.insert(strip_device(target), setting.to_string());
+            }
+            settings.insert(key, entries);
+        }
+
+        let entries = |key: &str| settings.get(key).cloned().unwrap_or_default();
+        let (fctemps, fcfans) = (entries("FCTEMPS"), entries("FCFANS"));
+        let mut channels = Vec::new();
+
+        for (pwm, temps) in fctemps.iter() {
+            let setting = |key: &str, default: Option<&str>| -> Result<String, String> {
+                settings.get(key)
+                    .and_then(|e| e.get(pwm))
+                    .map(String::as_str)
+                    .or(default)
+                    .map(str::to_string)
+                    .ok_or_else(|| format!("{key} has no value for {pwm}"))
+            };
+            let number = |key: &str| setting(key, None)?.parse::<i32>().map_err(|_| format!("{key} for {pwm} must be a number"));
+            let duty = |key: &str, default: Option<&str>| setting(key, default)?.parse::<Duty>().map_err(|e| format!("{key} for {pwm}: {e}"));
+
+            let channel = Channel {
+                pwm: pwm.clone(),
+                temps: temps.split('+').map(strip_device).collect(),
+                fans: fcfans.get(pwm).map(|f| f.split('+').map(strip_device).collect()).unwrap_or_default(),
+                min_temp: number("MINTEMP")?,
+                max_temp: number("MAXTEMP")?,
+                min_start: duty("MINSTART", None)?,
+                min_stop: duty("MINSTOP", None)?,
+                min_pwm: duty("MINPWM", Some("0"))?,
+                max_pwm: duty("MAXPWM", Some("255"))?,
+            };
+
+            if channel.max_temp <= channel.min_temp {
+                return Err(format!("MAXTEMP for {pwm} must be above its MINTEMP"));
+            }
+
+            channels.push(channel);
+        }
+
+        let devpath = entries("DEVPATH").into_iter().map(|(k, v)| (k, PathBuf::from(v))).collect();
+        return Ok(Self { interval_secs, devpath, devname: entries("DEVNAME"), channels });
+    }
+
+    // Pairs the fans and sets a curve for every channel it can find on this machine. Returns the number imported.
+    pub fn import(&self, service: &mut FanService) -> usize {
+        service.config.interval_ms = self.interval_secs.max(1) * 1000;
+        let mut imported = 0;
+
+        for channel in self.channels.iter() {
+            match self.import_channel(service, channel) {
+                Ok(()) => imported += 1,
+                Err(e) => log::warn!("Skipping {}: {e}", channel.pwm),
+            }
+        }
+
+        return imported;
+    }
+
+    fn import_channel(&self, service: &mut FanService, channel: &Channel) -> Result<(), String> {
+        let (pwm_chip, pwm_attribute) = self.locate(&service.hwmons, &channel.pwm)?;
+        let pwm_index = pwm_attribute.strip_prefix("pwm").ok_or_else(|| format!("{} is not a pwm", channel.pwm))?.to_string();
+        let pwm = service.hwmons[pwm_chip].pwms.iter().find(|p| p.index == pwm_index).cloned().ok_or_else(|| format!("{} has no {pwm_attribute}", service.hwmons[pwm_chip].id))?;
+
+        let mut temps = Vec::new();
+        for temp in channel.temps.iter() {
+            let (chip, attribute) = self.locate(&service.hwmons, temp)?;
+            let hwmon = &service.hwmons[chip];
+            let sensor = hwmon.temps.iter().find(|t| format!("temp{}_input", t.index) == attribute).ok_or_else(|| format!("{} has no {attribute}", hwmon.id))?;
+            temps.push(TempRef::new(hwmon, sensor));
+        }
+
+        for fan in channel.fans.iter() {
+            let (chip, attribute) = self.locate(&service.hwmons, fan)?;
+            if chip != pwm_chip {
+                log::warn!("{fan} is on another chip than {}, fans can only be paired with a pwm on their own chip", channel.pwm);
+                continue;
+            }
+
+            let hwmon = &mut service.hwmons[chip];
+            match hwmon.fans.iter_mut().find(|f| format!("fan{}_input", f.index) == attribute) {
+                // Already paired with this pwm, its calibration was measured against it and still holds
+                Some(f) if f.paired_pwm.as_ref().is_some_and(|p| p.is_same_channel(&pwm)) => {}
+                Some(f) => f.pair_with(pwm.clone(), None),
+                None => log::warn!("{} has no {attribute}", hwmon.id),
+            }
+        }
+
+        // A duty of MINPWM up to MINTEMP, then a straight line from MINSTOP to MAXPWM at MAXTEMP
+        let mut points = vec![CurvePoint { temp: channel.min_temp as f32, duty: channel.min_pwm }];
+        if channel.min_stop != channel.min_pwm {
+            points.push(CurvePoint { temp: channel.min_temp as f32, duty: channel.min_stop });
+        }
+        points.push(CurvePoint { temp: channel.max_temp as f32, duty: channel.max_pwm });
+
+        let source = SourceConfig { aggregate: Aggregate::Max, temps };
+        service.store_pairings(pwm_chip);
+
+        // MINSTOP is the lowest duty the fan keeps turning at, which makes it the curve's floor. It isn't a
+        // measurement, so it doesn't become a calibration. MINSTART only matters to spin up a stopped fan
+        let curve = service.config.set_pwm_curve_for(&service.hwmons[pwm_chip], &pwm_index, source, Curve::new(points));
+        curve.min_duty = (channel.min_stop != Duty::OFF).then_some(channel.min_stop);
+
+        return Ok(());
+    }
+
+    // Finds the chip a path like "hwmon1/pwm2" is on, by its DEVPATH and DEVNAME when the file has them.
+    fn locate(&self, hwmons: &[Hwmon], path: &str) -> Result<(usize, String), String> {
+        let (dir, attribute) = path.split_once('/').ok_or_else(|| format!("expected hwmonN/attribute, got '{path}'"))?;
+        let name = self.devname.get(dir);
+
+        let found = match self.devpath.get(dir) {
+            Some(devpath) => hwmons.iter().position(|h| h.device.as_ref().is_some_and(|d| &d.path == devpath) && name.is_none_or(|n| &h.name == n)),
+            None => hwmons.iter().position(|h| h.dir_name() == dir && name.is_none_or(|n| &h.name == n)),
+        };
+
+        match found {
+            Some(chip) => Ok((chip, attribute.to_string())),
+            None => Err(format!("no chip matches {dir}{}", name.map(|n| format!(" ({n})")).unwrap_or_default())),
+        }
+    }
+
+    // Every paired pwm with a curve becomes a channel. fancontrol only knows straight line curves on a single temp,
+    // so anything else is approximated and logged.
+    pub fn export(service: &FanService) -> Self {
+        let mut file = Self {
+            interval_secs: service.config.interval().as_secs().max(1),
+            devpath: BTreeMap::new(),
+            devname: BTreeMap::new(),
+            channels: Vec::new(),
+        };
+        if service.config.interval() < Duration::from_secs(1) {
+            log::warn!("fancontrol counts INTERVAL in whole seconds, exporting {}ms as 1", service.config.interval_ms);
+        }
+
+        for hwmon in service.hwmons.iter() {
+            for pwm in hwmon.paired_pwms() {
+                let curve_config = match service.config.curve_for_pwm(hwmon, &pwm.index) {
+                    Some(c) => c,
+                    None => continue,
+                };
+                let name = format!("{}/pwm{}", hwmon.dir_name(), pwm.index);
+
+                let temps = match curve_config.source_for(hwmon) {
+                    Some(source) => source.temps,
+                    None => continue,
+                };
+                if temps.len() > 1 {
+                    log::warn!("{name} follows {} temps, fancontrol only takes one so only {} is exported", temps.len(), temps[0].temp);
+                }
+                let temp = match temps.first().and_then(|t| service.hwmons.iter().find(|h| h.id == t.chip).zip(t.resolve(&service.hwmons))) {
+                    Some((temp_hwmon, temp)) => {
+                        file.add_chip(temp_hwmon);
+                        format!("{}/temp{}_input", temp_hwmon.dir_name(), temp.index)
+                    }
+                    None => {
+                        log::warn!("{name} follows a temp that no longer exists, skipping it");
+                        continue;
+                    }
+                };
+
+                let mut fans: Vec<_> = hwmon.fans.iter().filter(|f| f.paired_pwm.as_ref().is_some_and(|p| p.index == pwm.index)).collect();
+                fans.sort_by_key(|f| f.index);
+                let calibrations: Vec<_> = fans.iter().filter_map(|f| f.calibration.as_ref()).collect();
+                let calibrated_stop = calibrations.iter().filter(|c| c.stall_duty != Duty::OFF).map(|c| Duty::new(c.stall_duty.raw().saturating_add(1))).max().unwrap_or(Duty::OFF);
+                let calibrated_start = calibrations.iter().map(|c| c.start_duty).max().unwrap_or(Duty::OFF);
+
+                let points = &curve_config.curve.points;
+                let (first, last) = match (points.first(), points.last()) {
+                    (Some(f), Some(l)) => (f, l),
+                    _ => continue,
+                };
+                let step = points.get(1).filter(|p| p.temp == first.temp && points.len() == 3);
+                if points.len() > 2 && step.is_none() {
+                    log::warn!("{name} has a curve of {} points, fancontrol only takes a straight line so it is exported from the first to the last", points.len());
+                }
+                let min_stop = step.map(|p| p.duty).unwrap_or(first.duty).max(calibrated_stop).max(curve_config.min_duty.unwrap_or(Duty::OFF));
+
+                file.add_chip(hwmon);
+                file.channels.push(Channel {
+                    pwm: name,
+                    temps: vec![temp],
+                    fans: fans.iter().map(|f| format!("{}/fan{}_input", hwmon.dir_name(), f.index)).collect(),
+                    min_temp: first.temp.round() as i32,
+                    max_temp: (last.temp.round() as i32).max(first.temp.round() as i32 + 1),
+                    min_start: calibrated_start.max(min_stop),
+                    min_stop,
+                    min_pwm: first.duty,
+                    max_pwm: last.duty,
+                });
+            }
+        }
+
+        file.channels.sort_by(|a, b| a.pwm.cmp(&b.pwm));
+        return file;
+    }
+
+    fn add_chip(&mut self, hwmon: &Hwmon) {
+        let dir = hwmon.dir_name().to_string();
+        if let Some(device) = &hwmon.device {
+            self.devpath.insert(dir.clone(), device.path.clone());
+        }
+        self.devname.insert(dir, hwmon.name.clone());
+    }
+}
+
+// Older pwmconfig versions wrote paths through the device, e.g. "hwmon1/device/pwm2".
+fn strip_device(path: &str) -> String {
+    path.replacen("/device/", "/", 1)
+}
+
+impl Display for FancontrolFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let line = |key: &str, values: Vec<String>| if values.is_empty() { String::new() } else { format!("{key}={}\n", values.join(" ")) };
+        let per_channel = |key: &str, value: &dyn Fn(&Channel) -> String| line(key, self.channels.iter().map(|c| format!("{}={}", c.pwm, value(c))).collect());
+
+        writeln!(f, "# Configuration file generated by fancontrol-rs, in the format of lm-sensors fancontrol")?;
+        writeln!(f, "INTERVAL={}", self.interval_secs)?;
+        write!(f, "{}", line("DEVPATH", self.devpath.iter().map(|(k, v)| format!("{k}={}", v.display())).collect()))?;
+        write!(f, "{}", line("DEVNAME", self.devname.iter().map(|(k, v)| format!("{k}={v}")).collect()))?;
+        write!(f, "{}", per_channel("FCTEMPS", &|c| c.temps.join("+")))?;
+        write!(f, "{}", line("FCFANS", self.channels.iter().filter(|c| !c.fans.is_empty()).map(|c| format!("{}={}", c.pwm, c.fans.join("+"))).collect()))?;
+        write!(f, "{}", per_channel("MINTEMP", &|c| c.min_temp.to_string()))?;
+        write!(f, "{}", per_channel("MAXTEMP", &|c| c.max_temp.to_string()))?;
+        write!(f, "{}", per_channel("MINSTART", &|c| c.min_start.to_string()))?;
+        write!(f, "{}", per_channel("MINSTOP", &|c| c.min_stop.to_string()))?;
+        write!(f, "{}", per_channel("MINPWM", &|c| c.min_pwm.to_string()))?;
+        write!(f, "{}", per_channel("MAXPWM", &|c| c.max_pwm.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, fake_sysfs::FakeHwmonTree, hwmon_service::HwmonService, units::Temperature};
+
+    const PWMCONFIG: &str = "
+# Configuration file generated by pwmconfig, changes will be lost
+INTERVAL=10
+DEVPATH=hwmon1=devices/platform/nct6775.656 hwmon2=devices/pci/0000:00:18.3
+DEVNAME=hwmon1=nct6775 hwmon2=k10temp
+FCTEMPS=hwmon1/device/pwm2=hwmon2/temp1_input hwmon1/pwm1=hwmon1/temp1_input
+FCFANS=hwmon1/pwm2=hwmon1/fan2_input+hwmon1/fan3_input hwmon1/pwm1=hwmon1/fan1_input
+MINTEMP=hwmon1/pwm2=40 hwmon1/pwm1=35
+MAXTEMP=hwmon1/pwm2=80 hwmon1/pwm1=60
+MINSTART=hwmon1/pwm2=150 hwmon1/pwm1=100
+MINSTOP=hwmon1/pwm2=90 hwmon1/pwm1=60
+MINPWM=hwmon1/pwm2=0
+MAXPWM=hwmon1/pwm1=200
+";
+
+    #[test]
+    fn imports_pwmconfig_files_and_exports_them_back() {
+        // Numbered differently than when pwmconfig ran, DEVPATH and DEVNAME still find the chips
+        let tree = FakeHwmonTree::new();
+        tree.chip(0, "k10temp").with_device("pci", "0000:00:18.3", "k10temp").with_temp(1, 60000);
+        tree.chip(3, "nct6775").with_device("platform", "nct6775.656", "nct6775")
+            .with_fan(1, 800).with_fan(2, 900).with_fan(3, 950).with_temp(1, 40000).with_pwm(1, 128, 5).with_pwm(2, 128, 5);
+
+        let file = FancontrolFile::parse(PWMCONFIG).unwrap();
+        assert_eq!(file.channels.len(), 2);
+        assert_eq!(file.channels[1].pwm, "hwmon1/pwm2");
+        assert_eq!(file.channels[1].fans, ["hwmon1/fan2_input", "hwmon1/fan3_input"]);
+        assert_eq!((file.channels[0].min_pwm, file.channels[0].max_pwm), (Duty::OFF, Duty::new(200)));
+
+        let mut hwmon_service = HwmonService::new(tree.root().to_path_buf()).unwrap();
+        hwmon_service.initialize_hwmons();
+        let mut service = FanService::new(hwmon_service, Config::default());
+        assert_eq!(file.import(&mut service), 2);
+        assert_eq!(service.config.interval_ms, 10_000);
+        assert_eq!(service.config.pairings.len(), 3);
+
+        let loops = service.control_loops();
+        assert_eq!(loops.len(), 2);
+        let cpu_loop = loops.iter().find(|l| l.pwms[0].index == "2").unwrap();
+        assert_eq!(cpu_loop.source.temps[0].0.input_path(), tree.root().join("hwmon0/temp1_input"));
+        assert_eq!(cpu_loop.min_duty, Duty::new(90));
+        assert!(service.hwmons.iter().flat_map(|h| h.fans.iter()).all(|f| f.calibration.is_none()));
+        let duty_for = |celsius: f32| cpu_loop.curve.duty_for(Temperature::from_celsius(celsius));
+        assert_eq!((duty_for(40.0), duty_for(41.0), duty_for(60.0), duty_for(90.0)), (Duty::OFF, Duty::new(94), Duty::new(173), Duty::MAX));
+
+        let exported = FancontrolFile::export(&service);
+        assert_eq!(exported.devname.get("hwmon3").map(String::as_str), Some("nct6775"));
+        assert_eq!(exported.devpath.get("hwmon0"), Some(&PathBuf::from("devices/pci/0000:00:18.3")));
+
+        let reparsed = FancontrolFile::parse(&exported.to_string()).unwrap();
+        assert_eq!(reparsed, exported);
+        let cpu = reparsed.channels.iter().find(|c| c.pwm == "hwmon3/pwm2").unwrap();
+        assert_eq!(cpu.temps, ["hwmon0/temp1_input"]);
+        assert_eq!(cpu.fans, ["hwmon3/fan2_input", "hwmon3/fan3_input"]);
+        assert_eq!((cpu.min_temp, cpu.max_temp, cpu.min_start, cpu.min_stop, cpu.min_pwm, cpu.max_pwm), (40, 80, Duty::new(90), Duty::new(90), Duty::OFF, Duty::MAX));
+    }
+
+    #[test]
+    fn rejects_incomplete_channels() {
+        assert!(FancontrolFile::parse("FCTEMPS=hwmon1/pwm1=hwmon1/temp1_input\nMINTEMP=hwmon1/pwm1=40").is_err());
+        assert!(FancontrolFile::parse("INTERVAL=soon").is_err());
+        assert!(FancontrolFile::parse("FCTEMPS=hwmon1/pwm1=hwmon1/temp1_input\nMINTEMP=hwmon1/pwm1=40\nMAXTEMP=hwmon1/pwm1=30\nMINSTART=hwmon1/pwm1=100\nMINSTOP=hwmon1/pwm1=60").is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+struct StderrLogger {
+    journal: bool,
+}
+
+static JOURNAL_LOGGER: StderrLogger = StderrLogger { journal: true };
+static PLAIN_LOGGER: StderrLogger = StderrLogger { journal: false };
+
+// In journal mode each line is prefixed with its sd-daemon(3) priority, e.g. "<4>",
+// so journald picks up the level when stderr is connected to it.
+pub fn init(journal: bool) {
+    let logger = if journal { &JOURNAL_LOGGER } else { &PLAIN_LOGGER };
+
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(max_level());
+    }
+}
+
+fn max_level() -> LevelFilter {
+    std::env::var("FANCONTROL_LOG")
+        .ok()
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(LevelFilter::Info)
+}
+
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        if self.journal {
+            eprintln!("<{}>{}", priority(record.level()), record.args());
+        } else {
+            eprintln!("{}: {}", record.level().as_str().to_lowercase(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
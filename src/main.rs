@@ -1,66 +1,105 @@
-use std::{env, process::{self, Command}};
+#![allow(clippy::needless_return, clippy::module_inception)]
 
-use crate::hwmon_service::HwmonService;
+use std::{env, path::PathBuf, process};
 
-mod hwmon_service;
-mod path_helpers; 
+use clap::Parser;
+
+use fancontrol::{config, hwmon_service};
+#[cfg(feature = "simulator")]
+use fancontrol::{fake_sysfs::FakeHwmonTree, simulator::{SimulatedChip, SimulatorHandle}};
+
+use crate::{cli::Cli, commands::Context};
+
+mod cli;
+mod commands;
+mod daemon;
+mod dashboard;
+mod logging;
 mod terminal_utils;
 mod program;
-mod hwmon;
 
 fn main() {
-    if !is_root() {
-        restart_as_root();
-    }
+    let cli = Cli::parse();
+    let command = match cli.command {
+        None if cli.daemon => Some(cli::Command::Run { metrics_listen: None, profile: None }),
+        command => command,
+    };
 
-    terminal_utils::clear_terminal();
+    let has_config = cli.config.is_some();
+    let mut context = Context {
+        config_path: cli.config.unwrap_or_else(|| PathBuf::from(config::DEFAULT_CONFIG_PATH)),
+        sysfs_root: cli.sysfs_root.unwrap_or_else(hwmon_service::default_root),
+        fahrenheit: cli.fahrenheit,
+    };
 
-    let mut hwmon_service = HwmonService::new();
-    hwmon_service.initialize_hwmons();
+    let is_run = matches!(command, Some(cli::Command::Run { .. }));
+    logging::init(is_run && (cli.daemon || env::var_os("JOURNAL_STREAM").is_some()));
 
-    let hwmon_index = match program::print_initial_select(&hwmon_service.hwmons) {
-        Ok(h) => h,
-        Err(e) => {
-            eprintln!("module selection failed: {e}");
-            std::process::exit(1);
-        }
-    };
+    let simulation = if cli.simulate { start_simulation(&mut context, has_config) } else { None };
 
-    terminal_utils::clear_terminal();
+    // Reading sysfs needs no privileges. Commands that always write pwms say so up front instead of failing halfway.
+    if simulation.is_none() && !is_root() && let Some(name) = needs_root(command.as_ref()) {
+        log::error!("{name} changes fan speeds and needs root, run it with sudo");
+        process::exit(commands::EXIT_ERROR);
+    }
 
-    let hwmon = match hwmon_service.hwmons.get_mut(hwmon_index) {
-        Some(h) => h,
-        None => {
-            eprintln!("Error finding hwmon");
-            process::exit(1);
-        }
+    let code = match command {
+        None => program::run_wizard(&context.config_path, context.sysfs_root.clone()),
+        Some(command) => commands::dispatch(command, &context),
     };
 
-    hwmon.initialize();
-    hwmon.set_all_pwm(100);
+    stop_simulation(simulation);
+    process::exit(code);
+}
+
+#[cfg(feature = "simulator")]
+type Simulation = (FakeHwmonTree, SimulatorHandle);
+// Without the feature `--simulate` exits before there could be one
+#[cfg(not(feature = "simulator"))]
+type Simulation = std::convert::Infallible;
 
-    hwmon.print_temps();
-    hwmon.print_fans();
-    hwmon.print_pwms();
+// Runs against a simulated chip in a temp dir, keeping the config there unless --config is given.
+#[cfg(feature = "simulator")]
+fn start_simulation(context: &mut Context, has_config: bool) -> Option<Simulation> {
+    let tree = FakeHwmonTree::new();
+    let handle = SimulatedChip::demo().spawn(&tree, 0);
 
-    if terminal_utils::get_yes_no_selection_default_yes("Attempt auto pairing?") {
-        hwmon.try_pair_fans_to_pwm();
-    } else {
-        hwmon.manual_pair_fan_to_pwm();
+    context.sysfs_root = tree.root().to_path_buf();
+    if !has_config {
+        context.config_path = tree.root().join("fancontrol-rs.toml");
     }
 
-    terminal_utils::clear_terminal();
+    return Some((tree, handle));
 }
 
+#[cfg(feature = "simulator")]
+fn stop_simulation(simulation: Option<Simulation>) {
+    if let Some((_tree, handle)) = simulation {
+        handle.stop();
+    }
+}
+
+#[cfg(not(feature = "simulator"))]
+fn start_simulation(_context: &mut Context, _has_config: bool) -> Option<Simulation> {
+    log::error!("--simulate needs a build with the simulator feature, e.g. `cargo run --features simulator`");
+    process::exit(commands::EXIT_ERROR);
+}
+
+#[cfg(not(feature = "simulator"))]
+fn stop_simulation(_simulation: Option<Simulation>) {}
+
 #[cfg(unix)]
 fn is_root() -> bool {
     // SAFETY: libc::geteuid has no side effects
     unsafe { libc::geteuid() == 0 }
 }
 
-fn restart_as_root() {
-    let exe = env::current_exe().unwrap_or_default();
-    let args = env::args().skip(1);
-    let status = Command::new("sudo").arg(exe).args(args).status().unwrap_or_default();
-    process::exit(status.code().unwrap_or(1));
-}
\ No newline at end of file
+fn needs_root(command: Option<&cli::Command>) -> Option<&'static str> {
+    match command {
+        None => Some("The setup wizard"),
+        Some(cli::Command::Run { .. }) => Some("run"),
+        Some(cli::Command::Pair { .. }) => Some("pair"),
+        Some(cli::Command::Calibrate { .. }) => Some("calibrate"),
+        _ => None,
+    }
+}
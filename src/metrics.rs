@@ -0,0 +1,216 @@
+use std::{fmt::Write as _, io::{self, BufRead, BufReader, Write}, net::{SocketAddr, TcpListener, TcpStream}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex}, thread, time::Duration};
+
+use crate::{hwmon::hwmon::Hwmon, snapshot::{self, ChipSnapshot}, watchdog::Fault};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+// A scraper that connects and never sends a request must not hold up the next one
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How the control loops have been doing since the daemon started.
+#[derive(Default)]
+pub struct Health {
+    read_errors: AtomicU64,
+    write_errors: AtomicU64,
+    faults: AtomicU64,
+    failsafe_trips: AtomicU64,
+    failsafe_active: AtomicBool,
+}
+
+// Readings and health in the Prometheus text format. The sensors are read on every scrape, not cached.
+pub struct Metrics {
+    hwmons: Mutex<Arc<Vec<Hwmon>>>,
+    pub health: Health,
+}
+
+impl Health {
+    pub fn record_fault(&self, fault: &Fault) {
+        match fault {
+            Fault::ReadFailed { .. } => self.read_errors.fetch_add(1, Ordering::Relaxed),
+            Fault::WriteFailed { .. } => self.write_errors.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+        self.faults.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Call on every tick, counts a trip each time the failsafe goes from inactive to active.
+    pub fn set_failsafe(&self, active: bool) {
+        let was_active = self.failsafe_active.swap(active, Ordering::Relaxed);
+        if active && !was_active {
+            self.failsafe_trips.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new(hwmons: Arc<Vec<Hwmon>>) -> Self {
+        Self { hwmons: Mutex::new(hwmons), health: Health::default() }
+    }
+
+    // Swaps in the chips found after a reload, the health counters keep counting.
+    pub fn set_hwmons(&self, hwmons: Arc<Vec<Hwmon>>) {
+        *self.hwmons.lock().unwrap() = hwmons;
+    }
+
+    pub fn render(&self) -> String {
+        let hwmons = Arc::clone(&self.hwmons.lock().unwrap());
+        let chips = snapshot::snapshot(&hwmons);
+        let mut out = String::new();
+
+        family(&mut out, "hwmon_temp_celsius", "gauge", "Temperature reported by the sensor.");
+        for chip in chips.iter() {
+            for temp in chip.temps.iter() {
+                if let Some(millicelsius) = temp.millicelsius {
+                    sample(&mut out, "hwmon_temp_celsius", &[("chip", &chip.id), ("index", &temp.index), ("label", &temp.label)], millicelsius as f64 / 1000.0);
+                }
+            }
+        }
+
+        family(&mut out, "hwmon_fan_rpm", "gauge", "Fan speed, labelled with the pwm it is paired to.");
+        for chip in chips.iter() {
+            for fan in chip.fans.iter() {
+                if let Some(rpm) = fan.rpm {
+                    let index = fan.index.to_string();
+                    let pwm = fan.paired_pwm.as_deref().unwrap_or_default();
+                    sample(&mut out, "hwmon_fan_rpm", &[("chip", &chip.id), ("index", &index), ("label", &fan.label), ("pwm", pwm)], rpm.get() as f64);
+                }
+            }
+        }
+
+        family(&mut out, "hwmon_pwm_duty", "gauge", "PWM duty from 0 to 255, labelled with the fans paired to it.");
+        for chip in chips.iter() {
+            for pwm in chip.pwms.iter() {
+                if let Some(duty) = pwm.duty {
+                    sample(&mut out, "hwmon_pwm_duty", &[("chip", &chip.id), ("index", &pwm.index), ("label", &pwm.name), ("fans", &paired_fans(chip, &pwm.name))], duty.raw() as f64);
+                }
+            }
+        }
+
+        family(&mut out, "hwmon_pwm_enable", "gauge", "PWM mode, 1 is manual and 2 or more is the chip's own automatic control.");
+        for chip in chips.iter() {
+            for pwm in chip.pwms.iter() {
+                if let Some(enable) = pwm.enable {
+                    sample(&mut out, "hwmon_pwm_enable", &[("chip", &chip.id), ("index", &pwm.index), ("label", &pwm.name), ("fans", &paired_fans(chip, &pwm.name))], enable as f64);
+                }
+            }
+        }
+
+        let health = &self.health;
+        family(&mut out, "fancontrol_read_errors_total", "counter", "Sensor reads by the control loops that failed.");
+        sample(&mut out, "fancontrol_read_errors_total", &[], health.read_errors.load(Ordering::Relaxed) as f64);
+        family(&mut out, "fancontrol_write_errors_total", "counter", "PWM writes by the control loops that failed.");
+        sample(&mut out, "fancontrol_write_errors_total", &[], health.write_errors.load(Ordering::Relaxed) as f64);
+        family(&mut out, "fancontrol_faults_total", "counter", "Readings the control loops did not trust, including failed reads and writes.");
+        sample(&mut out, "fancontrol_faults_total", &[], health.faults.load(Ordering::Relaxed) as f64);
+        family(&mut out, "fancontrol_failsafe_trips_total", "counter", "Times every managed fan was forced to full speed.");
+        sample(&mut out, "fancontrol_failsafe_trips_total", &[], health.failsafe_trips.load(Ordering::Relaxed) as f64);
+        family(&mut out, "fancontrol_failsafe_active", "gauge", "1 while every managed fan is forced to full speed.");
+        sample(&mut out, "fancontrol_failsafe_active", &[], if health.failsafe_active.load(Ordering::Relaxed) { 1.0 } else { 0.0 });
+
+        return out;
+    }
+
+    // Serves GET /metrics on `addr` from a background thread. Returns the bound address, useful with port 0.
+    pub fn serve(self: &Arc<Self>, addr: SocketAddr) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let metrics = Arc::clone(self);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|s| metrics.respond(s));
+                if let Err(e) = result {
+                    log::warn!("Metrics request failed: {e}");
+                }
+            }
+        });
+
+        return Ok(local_addr);
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+        let mut request_line = String::new();
+        BufReader::new(&stream).read_line(&mut request_line)?;
+        let mut parts = request_line.split_whitespace();
+
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render()),
+            (Some("GET"), _) => ("404 Not Found", "Metrics are at /metrics\n".to_string()),
+            _ => ("405 Method Not Allowed", String::new()),
+        };
+
+        write!(stream, "HTTP/1.1 {status}\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len())?;
+        return stream.flush();
+    }
+}
+
+fn paired_fans(chip: &ChipSnapshot, pwm_name: &str) -> String {
+    chip.fans.iter()
+        .filter(|f| f.paired_pwm.as_deref() == Some(pwm_name))
+        .map(|f| format!("fan{}", f.index))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{k}=\"{}\"", escape(v))).collect();
+    if labels.is_empty() {
+        let _ = writeln!(out, "{name} {value}");
+    } else {
+        let _ = writeln!(out, "{name}{{{}}} {value}", labels.join(","));
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::{error::Error, fake_sysfs::FakeHwmonTree, hwmon_service::HwmonService};
+
+    #[test]
+    fn serves_readings_and_health_over_http() {
+        let tree = FakeHwmonTree::new();
+        tree.chip(0, "nct6775").with_device("platform", "nct6775.656", "nct6775")
+            .with_fan(1, 800).with_fan(2, 0).with_temp(1, 42500).with_temp_label(1, "CPU \"Socket\"").with_pwm(2, 128, 1);
+
+        let mut hwmon_service = HwmonService::new(tree.root().to_path_buf()).unwrap();
+        hwmon_service.initialize_hwmons();
+        let pwm = hwmon_service.hwmons[0].pwms[0].clone();
+        hwmon_service.hwmons[0].fans.iter_mut().find(|f| f.index == 1).unwrap().pair_with(pwm, None);
+
+        let metrics = Arc::new(Metrics::new(Arc::new(hwmon_service.hwmons)));
+        let fault = Fault::ReadFailed { sensor: "temp1".into(), error: Error::NotFound { path: "temp1_input".into() } };
+        metrics.health.record_fault(&fault);
+        metrics.health.set_failsafe(true);
+        metrics.health.set_failsafe(true);
+        metrics.health.set_failsafe(false);
+
+        let addr = metrics.serve("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let chip = "nct6775:nct6775@platform:nct6775.656";
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains(&format!("hwmon_temp_celsius{{chip=\"{chip}\",index=\"1\",label=\"CPU \\\"Socket\\\"\"}} 42.5\n")));
+        assert!(response.contains(&format!("hwmon_fan_rpm{{chip=\"{chip}\",index=\"1\",label=\"\",pwm=\"pwm2\"}} 800\n")));
+        assert!(response.contains(&format!("hwmon_fan_rpm{{chip=\"{chip}\",index=\"2\",label=\"\",pwm=\"\"}} 0\n")));
+        assert!(response.contains(&format!("hwmon_pwm_duty{{chip=\"{chip}\",index=\"2\",label=\"pwm2\",fans=\"fan1\"}} 128\n")));
+        assert!(response.contains(&format!("hwmon_pwm_enable{{chip=\"{chip}\",index=\"2\",label=\"pwm2\",fans=\"fan1\"}} 1\n")));
+        assert!(response.contains("fancontrol_read_errors_total 1\n"));
+        assert!(response.contains("fancontrol_failsafe_trips_total 1\n"));
+        assert!(response.contains("fancontrol_failsafe_active 0\n"));
+    }
+}
@@ -0,0 +1,208 @@
+use std::{collections::HashSet, fmt::{self, Display, Formatter}, fs, path::Path, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+const PROC_ROOT: &str = "/proc";
+
+// Switches to `profile` while everything it names holds, e.g. performance while `cargo` runs.
+// A rule that names nothing always matches, which makes it a fallback at the end of the list.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProfileRule {
+    pub profile: String,
+    // A running process with this name, as in /proc/PID/comm
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process: Option<String>,
+    // Local time of day as "22:00-07:00", it may wrap past midnight
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub between: Option<TimeRange>,
+}
+
+// Minutes since midnight, the end is exclusive.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeRange {
+    start: u32,
+    end: u32,
+}
+
+// What the rules are checked against.
+pub struct Environment {
+    pub processes: HashSet<String>,
+    pub minute_of_day: u32,
+}
+
+// Why a profile is active.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reason {
+    // Picked over the socket or the CLI
+    Selected,
+    Rule(usize),
+    Default,
+}
+
+impl ProfileRule {
+    pub fn matches(&self, environment: &Environment) -> bool {
+        let process = self.process.as_ref().is_none_or(|p| environment.processes.contains(p));
+        let time = self.between.is_none_or(|b| b.contains(environment.minute_of_day));
+        return process && time;
+    }
+}
+
+impl TimeRange {
+    pub fn contains(&self, minute_of_day: u32) -> bool {
+        if self.start <= self.end {
+            return (self.start..self.end).contains(&minute_of_day);
+        }
+
+        return minute_of_day >= self.start || minute_of_day < self.end;
+    }
+}
+
+impl Environment {
+    // Only lists processes when a rule needs them, that's a read of every /proc/PID/comm.
+    pub fn current(config: &Config) -> Self {
+        let processes = match config.rules.iter().any(|r| r.process.is_some()) {
+            true => running_processes(Path::new(PROC_ROOT)),
+            false => HashSet::new(),
+        };
+
+        Self { processes, minute_of_day: local_minute_of_day() }
+    }
+}
+
+// The profile to run: the selected one, else the first matching rule, else the config's default.
+// Rules naming a profile that doesn't exist are skipped.
+pub fn choose(config: &Config, selected: Option<&str>, environment: &Environment) -> (String, Reason) {
+    if let Some(name) = selected && config.has_profile(name) {
+        return (name.to_string(), Reason::Selected);
+    }
+
+    for (i, rule) in config.rules.iter().enumerate() {
+        if config.has_profile(&rule.profile) && rule.matches(environment) {
+            return (rule.profile.clone(), Reason::Rule(i));
+        }
+    }
+
+    return (config.default_profile().to_string(), Reason::Default);
+}
+
+fn running_processes(proc_root: &Path) -> HashSet<String> {
+    let entries = match fs::read_dir(proc_root) {
+        Ok(e) => e,
+        Err(e) => {
+            log::warn!("Unable to list processes in {}: {e}", proc_root.display());
+            return HashSet::new();
+        }
+    };
+
+    entries.flatten()
+        .filter(|e| e.file_name().to_str().is_some_and(|n| n.bytes().all(|b| b.is_ascii_digit())))
+        .filter_map(|e| fs::read_to_string(e.path().join("comm")).ok())
+        .map(|comm| comm.trim_end().to_string())
+        .collect()
+}
+
+fn local_minute_of_day() -> u32 {
+    // SAFETY: time(NULL) has no side effects and localtime_r only writes to `tm`
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return 0;
+        }
+        return (tm.tm_hour * 60 + tm.tm_min) as u32;
+    }
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Selected => write!(f, "selected"),
+            Reason::Rule(i) => write!(f, "rule {}", i + 1),
+            Reason::Default => write!(f, "default"),
+        }
+    }
+}
+
+impl Display for TimeRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}-{:02}:{:02}", self.start / 60, self.start % 60, self.end / 60, self.end % 60)
+    }
+}
+
+impl FromStr for TimeRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_time = |t: &str| -> Option<u32> {
+            let (hours, minutes) = t.trim().split_once(':')?;
+            let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+            (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+        };
+
+        match s.split_once('-').and_then(|(start, end)| Some((parse_time(start)?, parse_time(end)?))) {
+            Some((start, end)) => Ok(Self { start, end }),
+            None => Err(format!("expected HH:MM-HH:MM, got '{s}'")),
+        }
+    }
+}
+
+impl TryFrom<String> for TimeRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<TimeRange> for String {
+    fn from(range: TimeRange) -> Self {
+        range.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_selected_profile_then_the_first_matching_rule() {
+        let config: Config = toml::from_str(r#"
+            profile = "balanced"
+
+            [[profiles]]
+            name = "balanced"
+
+            [[profiles]]
+            name = "silent"
+
+            [[profiles]]
+            name = "performance"
+
+            [[rules]]
+            profile = "performance"
+            process = "cargo"
+
+            [[rules]]
+            profile = "silent"
+            between = "22:00-07:30"
+
+            [[rules]]
+            profile = "missing"
+        "#).unwrap();
+        assert_eq!(config.rules[1].between.unwrap().to_string(), "22:00-07:30");
+        assert!("7:00-25:00".parse::<TimeRange>().is_err());
+
+        let at = |minute_of_day: u32, processes: &[&str]| Environment { processes: processes.iter().map(|p| p.to_string()).collect(), minute_of_day };
+
+        assert_eq!(choose(&config, None, &at(12 * 60, &[])), ("balanced".to_string(), Reason::Default));
+        assert_eq!(choose(&config, None, &at(23 * 60, &["bash"])), ("silent".to_string(), Reason::Rule(1)));
+        assert_eq!(choose(&config, None, &at(7 * 60, &[])).0, "silent");
+        assert_eq!(choose(&config, None, &at(7 * 60 + 30, &[])).0, "balanced");
+        assert_eq!(choose(&config, None, &at(23 * 60, &["cargo"])), ("performance".to_string(), Reason::Rule(0)));
+        assert_eq!(choose(&config, Some("default"), &at(23 * 60, &["cargo"])), ("default".to_string(), Reason::Selected));
+        assert_eq!(choose(&config, Some("gone"), &at(12 * 60, &[])).1, Reason::Default);
+    }
+}
@@ -1,74 +1,349 @@
-use std::io;
+use std::{path::{Path, PathBuf}, sync::{atomic::Ordering, Arc}};
 
-use crate::{hwmon::hwmon::Hwmon, terminal_utils};
+use fancontrol::{config::Config, curve::Curve, fan_service::FanService, hwmon::{calibration::Sweep, hwmon::Hwmon, pairing::{self, Ramp}, pwm_state::{self, PwmStateGuard}}, hwmon_service::HwmonService, temp_source::{Aggregate, SourceConfig, TempRef}, units::Duty};
 
-pub fn print_initial_select(hwmons: &[Hwmon]) -> Result<usize, SelectError> {
-    if hwmons.is_empty() {
-        eprintln!("No hwmon");
-        return Err(SelectError::Io(io::Error::new(io::ErrorKind::NotSeekable, "no hwmons found")));
+use crate::{commands, terminal_utils};
+
+const DEFAULT_CURVE: &str = "30:60,50:120,70:200,80:255";
+
+pub fn run_wizard(config_path: &Path, sysfs_root: PathBuf) -> i32 {
+    let _pwm_state_guard = PwmStateGuard::new();
+    pwm_state::restore_on_signal();
+
+    terminal_utils::clear_terminal();
+
+    let config = match commands::load_config_for_update(config_path) {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+
+    let mut hwmon_service = match HwmonService::new(sysfs_root) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Unable to discover hwmon chips: {e}");
+            return commands::exit_code(&e);
+        }
+    };
+    hwmon_service.initialize_hwmons();
+    let mut service = FanService::new(hwmon_service, config);
+
+    let chips = service.pairable();
+    if chips.is_empty() {
+        eprintln!("No chip has both fans and pwm outputs");
+        return commands::EXIT_NOT_FOUND;
     }
 
-    let fan_modules: Vec<_> = hwmons.iter().filter(|h| h.fans.len() > 0).collect();
+    for chip in chips {
+        let hwmon = &mut service.hwmons[chip];
+
+        let run_pairing = if hwmon.has_pairings() {
+            println!("{}", hwmon.name);
+            print_pairings(hwmon);
+            terminal_utils::get_yes_no_selection_default_no(format!("Saved pairings found on {}, run pairing again?", hwmon.name).as_str())
+        } else {
+            terminal_utils::get_yes_no_selection_default_yes(format!("Pair the fans on {} ({} fans, {} pwm outputs)?", hwmon.name, hwmon.fans.len(), hwmon.pwms.len()).as_str())
+        };
+
+        if run_pairing {
+            terminal_utils::clear_terminal();
+            let auto = terminal_utils::get_yes_no_selection_default_yes("Attempt auto pairing?");
+            pair_fans(hwmon, auto);
 
-    if fan_modules.len() == 1 {
-        let fan_module = fan_modules[0];
+            service.store_pairings(chip);
+            save_config(&service.config, config_path);
+        }
 
-        match hwmons.iter().position(|module| module.path() == fan_module.path()) {
-            Some(i) => {
-                if terminal_utils::get_yes_no_selection_default_yes(format!("Only one module with fans found, continue with {}?", fan_module.name).as_str()) {
-                    return Ok(i)
-                } else {
-                    terminal_utils::clear_terminal();
-                }
-            },
-            _ => {}
+        let hwmon = &mut service.hwmons[chip];
+        if hwmon.has_pairings() && terminal_utils::get_yes_no_selection_default_no(format!("Calibrate paired fans on {}? This measures their start and stall duty and takes a few minutes", hwmon.name).as_str()) {
+            calibrate_fans(hwmon);
+            service.store_pairings(chip);
+            save_config(&service.config, config_path);
         }
     }
 
-    println!("Select a module: ");
-    for (i, h) in hwmons.iter().enumerate() {
-        println!("{i}: {} ({} fans, {} temp sensors, {} pwm inputs)", h.name, h.fans.len(), h.temps.len(), h.pwms.len());
+    if !service.has_pairings() {
+        println!("No fans paired, nothing to control");
+        return commands::EXIT_OK;
     }
 
-    let module_index = terminal_utils::read_usize("");
+    for chip in 0..service.hwmons.len() {
+        let hwmon = &service.hwmons[chip];
+        if !hwmon.has_pairings() {
+            continue;
+        }
+
+        let edit_curve = match (service.config.curve_for(hwmon), service.curve_source(chip)) {
+            (Some(c), Some(source)) => {
+                println!("Saved curve for {}: {source} {}", hwmon.name, c.curve);
+                terminal_utils::get_yes_no_selection_default_no("Edit fan curve?")
+            }
+            _ => true,
+        };
 
-    if module_index < hwmons.len() {
-        return Ok(module_index);
+        if !edit_curve {
+            continue;
+        }
+
+        println!("Curve for the fans on {}", hwmon.name);
+        if let Some((source, curve)) = select_curve(&service.hwmons) {
+            service.set_curve(chip, source, curve);
+            save_config(&service.config, config_path);
+        }
+    }
+
+    let control_loops = service.control_loops();
+    if control_loops.is_empty() {
+        return commands::EXIT_OK;
+    }
+
+    for control_loop in control_loops.iter() {
+        println!("Controlling {} from {} with curve {}", control_loop.pwms.iter().map(|p| p.name.as_str()).collect::<Vec<_>>().join(", "), control_loop.source, control_loop.curve);
+    }
+
+    let handles: Vec<_> = control_loops.into_iter().map(|l| l.spawn()).collect();
+    terminal_utils::wait_for_user_input();
+    handles.into_iter().for_each(|h| h.stop());
+
+    return commands::EXIT_OK;
+}
+
+pub fn pair_fans(hwmon: &mut Hwmon, auto: bool) {
+    terminal_utils::clear_terminal();
+    hwmon.clear_pairings();
+    hwmon.set_all_pwm(Duty::new(100));
+
+    print_temps(hwmon);
+    print_fans(hwmon);
+    print_pwms(hwmon);
+
+    if auto {
+        auto_pair(hwmon);
     } else {
-        handle_selection_error(module_index, hwmons)
+        manual_pair(hwmon);
+    }
+
+    terminal_utils::clear_terminal();
+}
+
+// Ramps each pwm while showing live fan speeds, then asks about the matches that aren't confident.
+fn auto_pair(hwmon: &mut Hwmon) {
+    let ramp = Ramp::default();
+    let mut responses = Vec::new();
+
+    for (i, pwm) in hwmon.pwms.iter().enumerate() {
+        let fans_arc = Arc::new(hwmon.fans.clone());
+        let header = Arc::new(format!("Ramping {} low to high and back, this takes about {}s...\n", pwm.name, ramp.duration().as_secs()));
+        let stop_flag = terminal_utils::spawn_live_fan_speed_thread(fans_arc, header);
+
+        responses.push(ramp.record(hwmon, i));
+        stop_flag.store(true, Ordering::Relaxed);
+    }
+
+    terminal_utils::clear_terminal();
+
+    let matches = pairing::match_fans(&responses, hwmon.fans.len());
+    let paired = hwmon.apply_matches(&matches, |fan, pwm, confidence| {
+        terminal_utils::get_yes_no_selection_default_no(format!("{} looks like it follows {} ({:.0}% confidence), pair them?", fan.label, pwm.name, confidence * 100.0).as_str())
+    });
+
+    println!("Paired {paired} of {} fans", hwmon.fans.len());
+    terminal_utils::wait_for_user_input();
+}
+
+// `pair --auto`: ramps each pwm without a terminal or prompts, matches that aren't confident are left unpaired.
+pub fn auto_pair_unattended(hwmon: &mut Hwmon) {
+    hwmon.clear_pairings();
+    hwmon.set_all_pwm(Duty::new(100));
+
+    let ramp = Ramp::default();
+    let responses: Vec<_> = (0..hwmon.pwms.len()).map(|i| ramp.record(hwmon, i)).collect();
+
+    let matches = pairing::match_fans(&responses, hwmon.fans.len());
+    let mut skipped = Vec::new();
+    let paired = hwmon.apply_matches(&matches, |fan, pwm, confidence| {
+        skipped.push(format!("{} may follow {} ({:.0}% confidence)", fan.label, pwm.name, confidence * 100.0));
+        false
+    });
+
+    println!("Paired {paired} of {} fans", hwmon.fans.len());
+    for skip in skipped.iter() {
+        println!("Not paired: {skip}, check it with `pair --manual`");
     }
 }
 
-fn handle_selection_error(module_index: usize, hwmons: &[Hwmon]) -> Result<usize, SelectError> {
-    let mut idx = module_index;
+// Sets each pwm to max speed in turn and lets the user pick the fan that sped up.
+fn manual_pair(hwmon: &mut Hwmon) {
+    for fan in hwmon.fans.iter_mut() {
+        fan.update_speed();
+    }
 
-    for attempt in 0..10 {
-        if idx < hwmons.len() {
-            return Ok(idx);
+    for pwm in hwmon.pwms.iter() {
+        terminal_utils::clear_terminal();
+        println!();
+        if let Err(e) = pwm.set_duty(Duty::MAX) {
+            println!("Unable to set {} to max speed: {e}", pwm.name);
+            terminal_utils::wait_for_user_input();
+            continue;
         }
 
-        if attempt == 9 {
-            return Err(SelectError::TooMany(10))
+        let fans_arc = Arc::new(hwmon.fans.clone());
+        let header = Arc::new(format!("Setting {} to max speed...", pwm.name).to_string());
+        let (stop_flag, index_receiver) = terminal_utils::spawn_live_fan_speed_thread_with_selection(fans_arc, header);
+
+        if let Ok(i) = index_receiver.recv() {
+            if let Some(fan) = &mut hwmon.fans.iter_mut().find(|f| f.index == i32::try_from(i).expect("Value too large for i32")) {
+                fan.pair_with(pwm.clone(), None);
+                let _ = pwm.set_duty(Duty::new(100));
+                stop_flag.store(true, Ordering::Relaxed);
+
+                println!("Paired {} to {}", pwm.name, fan.label);
+                terminal_utils::wait_for_user_input();
+                continue;
+            } else {
+                println!("Error pairing fan...");
+                terminal_utils::wait_for_user_input();
+                continue;
+            };
+        } else {
+            println!("{} not paried to any fan", pwm.name);
+            let _ = pwm.set_duty(Duty::new(100));
+            terminal_utils::wait_for_user_input();
+            continue;
         }
+    }
+}
+
+pub fn print_temps(hwmon: &Hwmon) {
+    println!("-- temps --");
+
+    for temp in hwmon.temps.iter() {
+        println!("{}: {}", temp.label, temp.get_formatted_temp())
+    }
+}
+
+pub fn print_fans(hwmon: &Hwmon) {
+    println!("-- fans --");
+
+    for fan in hwmon.fans.iter() {
+        println!("{}: {}", fan.label, fan.get_formatted_speed())
+    }
+}
+
+pub fn print_pwms(hwmon: &Hwmon) {
+    println!("-- pwms --");
+
+    for pwm in hwmon.pwms.iter() {
+        match pwm.read_duty() {
+            Ok(duty) => println!("{}: {duty}", pwm.name),
+            Err(e) => println!("{}: {}", pwm.name, e.reason()),
+        }
+    }
+}
 
-        idx = terminal_utils::read_usize(&format!("{idx} not a valid selection, range is 0 - {}", hwmons.len() - 1));
+pub fn print_pairings(hwmon: &Hwmon) {
+    println!("-- pairings --");
+
+    for fan in hwmon.fans.iter() {
+        match &fan.paired_pwm {
+            Some(pwm) => println!("{}: {}", fan.label, pwm.name),
+            None => println!("{}: not paired", fan.label),
+        }
     }
-    unreachable!()
 }
 
+// Sweeps every paired pwm and stores the results on its fans. Returns the number of fans calibrated.
+pub fn calibrate_fans(hwmon: &mut Hwmon) -> usize {
+    let sweep = Sweep::default();
+    let mut calibrated = 0;
+
+    for pwm in hwmon.paired_pwms() {
+        println!("Calibrating {}, this takes about {}s...", pwm.name, sweep.duration().as_secs());
 
+        for (fan, calibration) in sweep.run(hwmon, &pwm) {
+            let fan = &mut hwmon.fans[fan];
+            println!("{}: stalls at {}, starts at {}, max {} RPM", fan.label, calibration.stall_duty, calibration.start_duty, calibration.max_rpm);
+            for point in calibration.table.iter() {
+                println!("  {:>3} -> {} RPM", point.duty, point.rpm);
+            }
+
+            fan.set_calibration(calibration);
+            calibrated += 1;
+        }
+    }
+
+    return calibrated;
+}
 
-#[derive(Debug)]
-pub enum SelectError {
-    Io(io::Error),
-    TooMany(usize),
+pub fn save_config(config: &Config, path: &Path) {
+    match config.save(path) {
+        Ok(_) => println!("Config saved to {}", path.display()),
+        Err(e) => eprintln!("Error saving config to {}: {e}", path.display()),
+    }
 }
-impl std::fmt::Display for SelectError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "err")
+
+// Any temp sensors on any chips can drive a curve, several are combined as their max, average or a weighted mix.
+pub fn select_curve(hwmons: &[Hwmon]) -> Option<(SourceConfig, Curve)> {
+    let temps: Vec<_> = hwmons.iter().flat_map(|h| h.temps.iter().map(move |t| (h, t))).collect();
+
+    if temps.is_empty() {
+        println!("No chip has temp sensors");
+        return None;
+    }
+
+    println!("Select one or more temp sensors, separated by commas: ");
+    for (i, (hwmon, temp)) in temps.iter().enumerate() {
+        println!("{i}: {}/{} ({})", hwmon.name, temp.label, temp.get_formatted_temp());
+    }
+
+    let selected = loop {
+        let input = terminal_utils::read_string("");
+        let indices: Result<Vec<usize>, _> = input.split(',').map(|i| i.trim().parse::<usize>()).collect();
+
+        match indices {
+            Ok(indices) if !indices.is_empty() && indices.iter().all(|i| *i < temps.len()) => break indices,
+            _ => eprintln!("Expected indices between 0 and {}, e.g. 0 or 0,2", temps.len() - 1),
+        }
+    };
+
+    let aggregate = if selected.len() > 1 {
+        loop {
+            match terminal_utils::read_string_default("Combine them as max, average or weighted (max)", "max").parse::<Aggregate>() {
+                Ok(a) => break a,
+                Err(e) => eprintln!("{e}"),
+            }
+        }
+    } else {
+        Aggregate::Max
+    };
+
+    let mut source = SourceConfig { aggregate, temps: Vec::new() };
+    for i in selected {
+        let (hwmon, temp) = temps[i];
+        let temp_ref = TempRef::new(hwmon, temp);
+
+        if aggregate == Aggregate::Weighted {
+            let weight = read_weight(format!("Weight for {}/{} (1)", hwmon.name, temp_ref.temp).as_str());
+            source.temps.push(temp_ref.with_weight(weight));
+        } else {
+            source.temps.push(temp_ref);
+        }
+    }
+
+    loop {
+        let points = terminal_utils::read_string_default(format!("Enter curve as temp:duty pairs ({DEFAULT_CURVE})").as_str(), DEFAULT_CURVE);
+
+        match Curve::parse(&points) {
+            Ok(curve) => return Some((source, curve)),
+            Err(e) => eprintln!("Invalid curve: {e}"),
+        }
     }
 }
-impl std::error::Error for SelectError {}
-impl From<io::Error> for SelectError {
-    fn from(e: io::Error) -> Self { SelectError::Io(e) }
+
+fn read_weight(prompt: &str) -> f32 {
+    loop {
+        match terminal_utils::read_string_default(prompt, "1").parse::<f32>() {
+            Ok(weight) if TempRef::is_valid_weight(weight) => return weight,
+            _ => eprintln!("Expected a weight of 0 or more"),
+        }
+    }
 }
@@ -0,0 +1,296 @@
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, thread::{self, JoinHandle}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::fake_sysfs::{FakeChip, FakeHwmonTree};
+
+const AMBIENT_TEMP: f32 = 30.0;
+const IDLE_TEMP: f32 = 75.0;
+
+#[derive(Clone)]
+pub struct SimulatedFan {
+    pub index: usize,
+    pub pwm_index: usize,
+    pub label: String,
+    pub max_rpm: f32,
+    pub stall_duty: u8,
+}
+
+// A virtual hwmon chip whose fanN_input follows writes to the linked pwmN.
+#[derive(Clone)]
+pub struct SimulatedChip {
+    pub name: String,
+    pub fans: Vec<SimulatedFan>,
+    pub lag: Duration,
+    pub noise_rpm: f32,
+    pub coupling: f32,
+    pub tick: Duration,
+}
+
+pub struct SimulatorHandle {
+    stop_flag: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+struct SimulatorState {
+    chip: SimulatedChip,
+    files: FakeChip,
+    rpms: Vec<f32>,
+    temp: f32,
+    rng: u64,
+}
+
+impl SimulatedFan {
+    pub fn new(index: usize, pwm_index: usize) -> Self {
+        Self { index, pwm_index, label: format!("Fan {index}"), max_rpm: 1800.0, stall_duty: 40 }
+    }
+
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = label.to_string();
+        return self;
+    }
+
+    pub fn with_max_rpm(mut self, max_rpm: f32) -> Self {
+        self.max_rpm = max_rpm;
+        return self;
+    }
+
+    pub fn with_stall_duty(mut self, stall_duty: u8) -> Self {
+        self.stall_duty = stall_duty;
+        return self;
+    }
+}
+
+impl SimulatedChip {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            fans: Vec::new(),
+            lag: Duration::ZERO,
+            noise_rpm: 0.0,
+            coupling: 0.0,
+            tick: Duration::from_millis(50),
+        }
+    }
+
+    // Three fans on their own headers, the case fan picks up some airflow from the others.
+    pub fn demo() -> Self {
+        Self::new("simulated")
+            .with_fan(SimulatedFan::new(1, 1).with_label("CPU Fan").with_max_rpm(2200.0))
+            .with_fan(SimulatedFan::new(2, 2).with_label("Rear Fan").with_max_rpm(1500.0).with_stall_duty(60))
+            .with_fan(SimulatedFan::new(3, 3).with_label("Front Fan").with_max_rpm(1200.0))
+            .with_lag(Duration::from_millis(800))
+            .with_noise(15.0)
+            .with_coupling(0.05)
+            .with_tick(Duration::from_millis(100))
+    }
+
+    pub fn with_fan(mut self, fan: SimulatedFan) -> Self {
+        self.fans.push(fan);
+        return self;
+    }
+
+    pub fn with_lag(mut self, lag: Duration) -> Self {
+        self.lag = lag;
+        return self;
+    }
+
+    pub fn with_noise(mut self, noise_rpm: f32) -> Self {
+        self.noise_rpm = noise_rpm;
+        return self;
+    }
+
+    pub fn with_coupling(mut self, coupling: f32) -> Self {
+        self.coupling = coupling;
+        return self;
+    }
+
+    pub fn with_tick(mut self, tick: Duration) -> Self {
+        self.tick = tick;
+        return self;
+    }
+
+    // Creates hwmon{index} in the tree and keeps its files updated until the handle is stopped.
+    pub fn spawn(self, tree: &FakeHwmonTree, index: usize) -> SimulatorHandle {
+        let mut files = tree.chip(index, &self.name)
+            .with_device("platform", &format!("{}.{index}", self.name), &self.name)
+            .with_temp(1, (IDLE_TEMP * 1000.0) as i32).with_temp_label(1, "Simulated CPU");
+
+        let mut pwm_indices: Vec<usize> = self.fans.iter().map(|f| f.pwm_index).collect();
+        pwm_indices.sort();
+        pwm_indices.dedup();
+        for pwm_index in pwm_indices {
+            files = files.with_pwm(pwm_index, 128, 2);
+        }
+
+        let rpms: Vec<f32> = self.fans.iter().map(|f| f.max_rpm * 128.0 / 255.0).collect();
+        for (fan, rpm) in self.fans.iter().zip(rpms.iter()) {
+            files = files.with_fan(fan.index, rpm.round() as i32).with_fan_label(fan.index, &fan.label);
+        }
+
+        log::info!("Simulating {} in {}", self.name, files.path().display());
+
+        let mut state = SimulatorState { chip: self, files, rpms, temp: IDLE_TEMP, rng: seed() };
+
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = Arc::clone(&stop_flag);
+
+        let thread = thread::spawn(move || {
+            while !stop_flag_clone.load(Ordering::Relaxed) {
+                state.step();
+                thread::sleep(state.chip.tick);
+            }
+        });
+
+        return SimulatorHandle { stop_flag, thread };
+    }
+}
+
+impl SimulatorHandle {
+    pub fn stop(self) {
+        self.stop_flag.store(true, Ordering::Relaxed);
+        let _ = self.thread.join();
+    }
+}
+
+impl SimulatorState {
+    fn step(&mut self) {
+        let dt = self.chip.tick.as_secs_f32();
+        let response = if self.chip.lag.is_zero() { 1.0 } else { 1.0 - (-dt / self.chip.lag.as_secs_f32()).exp() };
+
+        // Keep the last speed if a pwm file was caught mid-write
+        let targets: Vec<f32> = self.chip.fans.iter()
+            .enumerate()
+            .map(|(i, fan)| self.target_rpm(fan).unwrap_or(self.rpms[i]))
+            .collect();
+        let total_target: f32 = targets.iter().sum();
+        let others = targets.len().saturating_sub(1).max(1) as f32;
+
+        for (i, fan) in self.chip.fans.iter().enumerate() {
+            let coupled = self.chip.coupling * (total_target - targets[i]) / others;
+            self.rpms[i] += (targets[i] + coupled - self.rpms[i]) * response;
+
+            let noise = if self.rpms[i] > 1.0 { self.chip.noise_rpm * next_noise(&mut self.rng) } else { 0.0 };
+            let rpm = (self.rpms[i] + noise).max(0.0).round();
+            self.files.write(&format!("fan{}_input", fan.index), &format!("{rpm}"));
+        }
+
+        let max_total: f32 = self.chip.fans.iter().map(|f| f.max_rpm).sum();
+        let airflow = if max_total > 0.0 { self.rpms.iter().sum::<f32>() / max_total } else { 0.0 };
+        let target_temp = IDLE_TEMP - (IDLE_TEMP - AMBIENT_TEMP - 10.0) * airflow;
+        self.temp += (target_temp - self.temp) * response * 0.2;
+        self.files.write("temp1_input", &format!("{}", (self.temp * 1000.0).round()));
+    }
+
+    fn target_rpm(&self, fan: &SimulatedFan) -> Option<f32> {
+        let duty = match self.read_pwm(fan.pwm_index, "_enable") {
+            // pwmN_enable 0 means no speed control, i.e. full speed
+            Some(0) => 255,
+            _ => self.read_pwm(fan.pwm_index, "")?,
+        };
+
+        if duty < u32::from(fan.stall_duty) {
+            return Some(0.0);
+        }
+
+        return Some(fan.max_rpm * duty.min(255) as f32 / 255.0);
+    }
+
+    fn read_pwm(&self, index: usize, suffix: &str) -> Option<u32> {
+        self.files.read(&format!("pwm{index}{suffix}")).parse().ok()
+    }
+}
+
+fn seed() -> u64 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+    return nanos | 1;
+}
+
+// Uniform noise in [-1, 1] from a xorshift64 generator.
+fn next_noise(state: &mut u64) -> f32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    return (*state >> 40) as f32 / (1u64 << 23) as f32 - 1.0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hwmon::{calibration::Sweep, pairing::{self, Ramp}}, hwmon_service::HwmonService, units::{Duty, Rpm}};
+
+    fn settle() {
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    #[test]
+    fn fan_speed_follows_linked_pwm_and_stalls_below_threshold() {
+        let tree = FakeHwmonTree::new();
+        let chip = SimulatedChip::new("sim")
+            .with_fan(SimulatedFan::new(1, 1).with_max_rpm(2000.0).with_stall_duty(50))
+            .with_tick(Duration::from_millis(5));
+        let handle = chip.spawn(&tree, 0);
+        let files = tree.root().join("hwmon0");
+
+        std::fs::write(files.join("pwm1"), "255").unwrap();
+        settle();
+        assert_eq!(std::fs::read_to_string(files.join("fan1_input")).unwrap().trim(), "2000");
+
+        std::fs::write(files.join("pwm1"), "30").unwrap();
+        settle();
+        assert_eq!(std::fs::read_to_string(files.join("fan1_input")).unwrap().trim(), "0");
+
+        handle.stop();
+    }
+
+    #[test]
+    fn auto_pairing_finds_fans_sharing_a_pwm() {
+        let tree = FakeHwmonTree::new();
+        let chip = SimulatedChip::new("sim")
+            .with_fan(SimulatedFan::new(1, 1))
+            .with_fan(SimulatedFan::new(2, 1).with_max_rpm(900.0))
+            .with_fan(SimulatedFan::new(3, 2))
+            .with_lag(Duration::from_millis(20))
+            .with_noise(10.0)
+            .with_coupling(0.05)
+            .with_tick(Duration::from_millis(5));
+        let handle = chip.spawn(&tree, 0);
+
+        let mut service = HwmonService::new(tree.root().to_path_buf()).unwrap();
+        service.initialize_hwmons();
+        let hwmon = &mut service.hwmons[0];
+        hwmon.fans.sort_by_key(|f| f.index);
+        hwmon.pwms.sort_by(|a, b| a.index.cmp(&b.index));
+
+        let ramp = Ramp { settle: Duration::from_millis(100), ..Ramp::default() };
+        let responses: Vec<_> = (0..hwmon.pwms.len()).map(|i| ramp.record(hwmon, i)).collect();
+        let matches = pairing::match_fans(&responses, hwmon.fans.len());
+        handle.stop();
+
+        assert_eq!(hwmon.apply_matches(&matches, |_, _, _| false), 3);
+        let pairs: Vec<_> = hwmon.fans.iter().map(|f| f.paired_pwm.as_ref().map(|p| p.name.as_str())).collect();
+        assert_eq!(pairs, [Some("pwm1"), Some("pwm1"), Some("pwm2")]);
+    }
+
+    #[test]
+    fn calibration_finds_the_stall_and_start_duty_of_a_simulated_fan() {
+        let tree = FakeHwmonTree::new();
+        let chip = SimulatedChip::new("sim")
+            .with_fan(SimulatedFan::new(1, 1).with_max_rpm(2000.0).with_stall_duty(60))
+            .with_tick(Duration::from_millis(5));
+        let handle = chip.spawn(&tree, 0);
+
+        let mut service = HwmonService::new(tree.root().to_path_buf()).unwrap();
+        service.initialize_hwmons();
+        let hwmon = &mut service.hwmons[0];
+        let pwm = hwmon.pwms[0].clone();
+        hwmon.fans[0].pair_with(pwm.clone(), None);
+
+        let sweep = Sweep { step: 51, settle: Duration::from_millis(100), samples: 1 };
+        let results = sweep.run(hwmon, &pwm);
+        handle.stop();
+
+        let (fan, calibration) = &results[0];
+        assert_eq!(*fan, 0);
+        assert_eq!((calibration.stall_duty, calibration.start_duty, calibration.max_rpm), (Duty::new(51), Duty::new(102), Rpm::new(2000)));
+        assert_eq!(calibration.table.len(), 6);
+    }
+}
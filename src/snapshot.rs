@@ -0,0 +1,86 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::{hwmon::{fans::Fan, hwmon::Hwmon, pwm::Pwm, temp::Temp}, units::{Duty, Rpm}};
+
+// Machine readable view of a chip and its current readings.
+#[derive(Serialize)]
+pub struct ChipSnapshot {
+    pub id: String,
+    pub name: String,
+    pub path: PathBuf,
+    pub fans: Vec<FanSnapshot>,
+    pub temps: Vec<TempSnapshot>,
+    pub pwms: Vec<PwmSnapshot>,
+}
+
+#[derive(Serialize)]
+pub struct FanSnapshot {
+    pub index: i32,
+    pub label: String,
+    pub min_rpm: Rpm,
+    pub max_rpm: Rpm,
+    pub rpm: Option<Rpm>,
+    pub paired_pwm: Option<String>,
+    pub pair_confidence: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct TempSnapshot {
+    pub index: String,
+    pub label: String,
+    pub millicelsius: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct PwmSnapshot {
+    pub index: String,
+    pub name: String,
+    pub duty: Option<Duty>,
+    pub enable: Option<u8>,
+}
+
+impl ChipSnapshot {
+    pub fn new(hwmon: &Hwmon) -> Self {
+        let mut fans: Vec<FanSnapshot> = hwmon.fans.iter().map(FanSnapshot::new).collect();
+        let mut temps: Vec<TempSnapshot> = hwmon.temps.iter().map(TempSnapshot::new).collect();
+        let mut pwms: Vec<PwmSnapshot> = hwmon.pwms.iter().map(PwmSnapshot::new).collect();
+
+        fans.sort_by_key(|f| f.index);
+        temps.sort_by_key(|t| t.index.parse::<u32>().unwrap_or(0));
+        pwms.sort_by_key(|p| p.index.parse::<u32>().unwrap_or(0));
+
+        Self { id: hwmon.id.clone(), name: hwmon.name.clone(), path: hwmon.path().to_path_buf(), fans, temps, pwms }
+    }
+}
+
+impl FanSnapshot {
+    fn new(fan: &Fan) -> Self {
+        Self {
+            index: fan.index,
+            label: fan.label.clone(),
+            min_rpm: fan.min_speed_rpm,
+            max_rpm: fan.max_speed_rpm,
+            rpm: fan.get_speed().ok(),
+            paired_pwm: fan.paired_pwm.as_ref().map(|p| p.name.clone()),
+            pair_confidence: fan.pair_confidence,
+        }
+    }
+}
+
+impl TempSnapshot {
+    fn new(temp: &Temp) -> Self {
+        Self { index: temp.index.clone(), label: temp.label.clone(), millicelsius: temp.get_temp().ok().map(|t| t.millicelsius()) }
+    }
+}
+
+impl PwmSnapshot {
+    fn new(pwm: &Pwm) -> Self {
+        Self { index: pwm.index.clone(), name: pwm.name.clone(), duty: pwm.read_duty().ok(), enable: pwm.read_enable().ok() }
+    }
+}
+
+pub fn snapshot(hwmons: &[Hwmon]) -> Vec<ChipSnapshot> {
+    hwmons.iter().map(ChipSnapshot::new).collect()
+}
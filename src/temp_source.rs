@@ -0,0 +1,231 @@
+use std::{fmt::{self, Display, Formatter}, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::{hwmon::{hwmon::Hwmon, temp::Temp}, units::Temperature};
+
+// How the temps of a source are combined into the one a curve acts on.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregate {
+    #[default]
+    Max,
+    Average,
+    Weighted,
+}
+
+// A temp sensor on any chip, by the chip's stable ID and the sensor's label, or tempN when it has none.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TempRef {
+    pub chip: String,
+    pub temp: String,
+    // Only used by the weighted aggregate
+    #[serde(default = "default_weight", deserialize_with = "deserialize_weight", skip_serializing_if = "is_default_weight")]
+    pub weight: f32,
+}
+
+// The temps a curve follows as saved in the config, e.g. the CPU and the NVMe drives for the case fans.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SourceConfig {
+    #[serde(default)]
+    pub aggregate: Aggregate,
+    pub temps: Vec<TempRef>,
+}
+
+// A source resolved to the sensors found on this boot.
+#[derive(Clone)]
+pub struct TempSource {
+    pub aggregate: Aggregate,
+    pub temps: Vec<(Temp, f32)>,
+}
+
+impl TempRef {
+    // Uses the label unless the chip has another temp with the same one.
+    pub fn new(hwmon: &Hwmon, temp: &Temp) -> Self {
+        let unique_label = !temp.label.is_empty() && hwmon.temps.iter().filter(|t| t.label == temp.label).count() == 1;
+        let name = if unique_label { temp.label.clone() } else { format!("temp{}", temp.index) };
+
+        Self { chip: hwmon.id.clone(), temp: name, weight: default_weight() }
+    }
+
+    pub fn with_weight(mut self, weight: f32) -> Self {
+        self.weight = weight;
+        return self;
+    }
+
+    // A weight has to be a number of 0 or more, an infinite one turns the mix into NaN and a negative one cools it down.
+    pub fn is_valid_weight(weight: f32) -> bool {
+        weight.is_finite() && weight >= 0.0
+    }
+
+    pub fn resolve(&self, hwmons: &[Hwmon]) -> Option<Temp> {
+        let hwmon = hwmons.iter().find(|h| h.id == self.chip)?;
+
+        hwmon.temps.iter()
+            .find(|t| t.label == self.temp)
+            .or_else(|| hwmon.temps.iter().find(|t| format!("temp{}", t.index) == self.temp))
+            .cloned()
+    }
+}
+
+impl SourceConfig {
+    pub fn single(temp: TempRef) -> Self {
+        Self { aggregate: Aggregate::Max, temps: vec![temp] }
+    }
+
+    // Every referenced sensor has to exist, a max that silently dropped the CPU would run the fans too slow.
+    pub fn resolve(&self, hwmons: &[Hwmon]) -> Option<TempSource> {
+        let mut temps = Vec::new();
+
+        for temp_ref in self.temps.iter() {
+            match temp_ref.resolve(hwmons) {
+                Some(temp) => temps.push((temp, temp_ref.weight)),
+                None => {
+                    log::warn!("{}: saved temp {} no longer exists", temp_ref.chip, temp_ref.temp);
+                    return None;
+                }
+            }
+        }
+
+        if temps.is_empty() {
+            return None;
+        }
+
+        return Some(TempSource { aggregate: self.aggregate, temps });
+    }
+}
+
+impl TempSource {
+    pub fn single(temp: Temp) -> Self {
+        Self { aggregate: Aggregate::Max, temps: vec![(temp, default_weight())] }
+    }
+
+    // Stands in for a source that didn't resolve, a loop following it always faults.
+    pub fn missing() -> Self {
+        Self { aggregate: Aggregate::Max, temps: Vec::new() }
+    }
+
+    // `readings` are in the same order as `temps`. Weights that add up to nothing fall back to the hottest reading.
+    pub fn combine(&self, readings: &[Temperature]) -> Temperature {
+        let hottest = readings.iter().copied().max().unwrap_or(Temperature::from_millicelsius(0));
+        if self.aggregate == Aggregate::Max {
+            return hottest;
+        }
+
+        let weights = self.temps.iter().map(|(_, w)| if self.aggregate == Aggregate::Weighted { *w } else { 1.0 });
+        let (sum, total) = readings.iter().zip(weights).fold((0.0, 0.0), |(sum, total), (t, w)| (sum + t.celsius() * w, total + w));
+
+        if total <= 0.0 {
+            return hottest;
+        }
+
+        return Temperature::from_celsius(sum / total);
+    }
+}
+
+impl Display for TempSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let names: Vec<String> = self.temps.iter()
+            .map(|(temp, weight)| {
+                let name = if temp.label.is_empty() { format!("temp{}", temp.index) } else { temp.label.clone() };
+                if self.aggregate == Aggregate::Weighted { format!("{name} ×{weight}") } else { name }
+            })
+            .collect();
+
+        if names.is_empty() {
+            return write!(f, "missing temps");
+        }
+        if names.len() == 1 {
+            return write!(f, "{}", names[0]);
+        }
+
+        write!(f, "{} of {}", self.aggregate, names.join(", "))
+    }
+}
+
+impl Display for Aggregate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Aggregate::Max => write!(f, "max"),
+            Aggregate::Average => write!(f, "average"),
+            Aggregate::Weighted => write!(f, "weighted mix"),
+        }
+    }
+}
+
+impl FromStr for Aggregate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "max" => Ok(Aggregate::Max),
+            "average" | "avg" => Ok(Aggregate::Average),
+            "weighted" => Ok(Aggregate::Weighted),
+            _ => Err(format!("expected max, average or weighted, got '{s}'")),
+        }
+    }
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+fn deserialize_weight<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    let weight = f32::deserialize(deserializer)?;
+    if !TempRef::is_valid_weight(weight) {
+        return Err(de::Error::custom(format!("expected a weight of 0 or more, got {weight}")));
+    }
+
+    return Ok(weight);
+}
+
+fn is_default_weight(weight: &f32) -> bool {
+    *weight == default_weight()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fake_sysfs::FakeHwmonTree, hwmon_service::HwmonService};
+
+    #[test]
+    fn resolves_temps_by_chip_and_label_and_combines_them() {
+        let tree = FakeHwmonTree::new();
+        tree.chip(0, "k10temp").with_device("pci", "0000:00:18.3", "k10temp").with_temp(1, 70000).with_temp_label(1, "Tctl");
+        tree.chip(1, "nvme").with_device("nvme", "nvme0", "nvme").with_temp(1, 40000).with_temp_label(1, "Composite").with_temp(2, 45000);
+
+        let mut service = HwmonService::new(tree.root().to_path_buf()).unwrap();
+        service.initialize_hwmons();
+        let nvme = &service.hwmons[1];
+        let unlabeled = nvme.temps.iter().find(|t| t.index == "2").unwrap();
+        assert_eq!(TempRef::new(nvme, unlabeled).temp, "temp2");
+
+        let mut source = SourceConfig {
+            aggregate: Aggregate::Max,
+            temps: vec![
+                TempRef { chip: "k10temp:k10temp@pci:0000:00:18.3".into(), temp: "Tctl".into(), weight: 3.0 },
+                TempRef { chip: "nvme:nvme@nvme:nvme0".into(), temp: "Composite".into(), weight: 1.0 },
+            ],
+        };
+        let read = |source: &TempSource| source.combine(&source.temps.iter().map(|(t, _)| t.get_temp().unwrap()).collect::<Vec<_>>());
+
+        let resolved = source.resolve(&service.hwmons).unwrap();
+        assert_eq!(read(&resolved).celsius(), 70.0);
+        assert_eq!(resolved.to_string(), "max of Tctl, Composite");
+
+        source.aggregate = Aggregate::Average;
+        assert_eq!(read(&source.resolve(&service.hwmons).unwrap()).celsius(), 55.0);
+
+        source.aggregate = Aggregate::Weighted;
+        assert_eq!(read(&source.resolve(&service.hwmons).unwrap()).celsius(), 62.5);
+
+        source.temps[1].temp = "Gone".into();
+        assert!(source.resolve(&service.hwmons).is_none());
+
+        let weighted = |weight: &str| toml::from_str::<TempRef>(&format!("chip = \"nvme\"\ntemp = \"Composite\"\nweight = {weight}"));
+        assert_eq!(weighted("0.5").unwrap().weight, 0.5);
+        assert!(weighted("inf").is_err());
+        assert!(weighted("nan").is_err());
+        assert!(weighted("-1.0").is_err());
+    }
+}
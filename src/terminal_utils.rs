@@ -1,32 +1,16 @@
-use std::{io::{self, stdout, BufRead, Read, Write}, os::fd::AsRawFd, sync::{atomic::{AtomicBool, Ordering}, mpsc::{self, Receiver}, Arc}, thread, time::Duration};
+use std::{io::{self, BufRead, Read, Write}, os::fd::AsRawFd, sync::{atomic::{AtomicBool, Ordering}, mpsc::{self, Receiver}, Arc}, thread, time::Duration};
 use libc::{self, termios as Termios};
-use crate::{hwmon::fans::Fan, terminal_utils};
-
-pub fn read_usize(prompt: &str) -> usize {
-    loop {
-        print!("{prompt}");
-        let _ = io::stdout().flush();
-
-        let mut s = String::new();
-        if io::stdin().read_line(&mut s).is_ok() {
-            match s.trim().parse::<usize>() {
-                Ok(n) => return n,
-                Err(e) => eprintln!("Please enter a non-negative integer: {e}"),
-            }
-        }
-    }
-}
+use crossterm::{cursor::MoveTo, queue, style::Print, terminal::{Clear, ClearType}};
+use fancontrol::hwmon::fans::Fan;
 
 pub fn read_string_default(prompt: &str, default: &str) -> String {
-    loop {
-        let val = read_string(prompt);
-
-        if val.trim().is_empty() {
-            return default.to_string();
-        }
+    let val = read_string(prompt);
 
-        return val;
+    if val.trim().is_empty() {
+        return default.to_string();
     }
+
+    return val;
 }
 
 pub fn read_string(prompt: &str) -> String {
@@ -46,7 +30,7 @@ pub fn read_string(prompt: &str) -> String {
 pub fn get_yes_no_selection<T>(prompt: &str, on_empty: T) -> bool
 where T : Fn() -> bool {
     loop {
-        let input = read_string(format!("{prompt}").as_str());
+        let input = read_string(prompt);
 
         if input.is_empty() {
             return on_empty();
@@ -110,7 +94,7 @@ pub fn spawn_live_fan_speed_thread(fans: Arc<Vec<Fan>>, header: Arc<String>) ->
     let _ = thread::spawn(move || -> std::io::Result<()> {
         let mut buffer = String::new();
         let mut sorted_fans: Vec<_> = fans_clone.iter().collect();
-        sorted_fans.sort_by(|f1, f2| f1.index.cmp(&f2.index));
+        sorted_fans.sort_by_key(|f| f.index);
 
         loop {
             if stop_flag_clone.load(Ordering::Relaxed) {
@@ -122,8 +106,7 @@ pub fn spawn_live_fan_speed_thread(fans: Arc<Vec<Fan>>, header: Arc<String>) ->
                 buffer.push_str(format!("{}: {} \n", fan.label, fan.get_formatted_speed()).as_str());
             }
 
-            terminal_utils::clear_terminal();
-            print!("{}", buffer);
+            redraw(&buffer);
             buffer.clear();
 
             thread::sleep(Duration::from_millis(100));
@@ -146,7 +129,7 @@ pub fn spawn_live_fan_speed_thread_with_selection(fans: Arc<Vec<Fan>>, header: A
     let _ = thread::spawn(move || -> std::io::Result<()> {
         let mut buffer = String::new();
         let mut sorted_fans: Vec<_> = fans_clone.iter().collect();
-        sorted_fans.sort_by(|f1, f2| f1.index.cmp(&f2.index));
+        sorted_fans.sort_by_key(|f| f.index);
 
         loop {
             if stop_flag_clone.load(Ordering::Relaxed) {
@@ -156,16 +139,15 @@ pub fn spawn_live_fan_speed_thread_with_selection(fans: Arc<Vec<Fan>>, header: A
    
             buffer.push_str(format!("{} \n", &header_clone).as_str());
             for fan in sorted_fans.iter() {
-                if fan.get_speed().abs_diff(fan.current_speed) > 200 {
-                    buffer.push_str(format!("\x1b[32m{}: {}\x1b[0m - {} (was {}) \n", fan.index, fan.label, fan.get_formatted_speed(), fan.get_formatted_cached_speed().as_str()).as_str());
+                if fan.get_speed().is_ok_and(|rpm| rpm.get().abs_diff(fan.current_speed.get()) > 200) {
+                    buffer.push_str(format!("\x1b[32m{}: {}\x1b[0m - {} (was {}) \n", fan.index, fan.label, fan.get_formatted_speed(), fan.current_speed).as_str());
                 } else {
-                    buffer.push_str(format!("{}: {} - {} (was {}) \n", fan.index, fan.label, fan.get_formatted_speed(), fan.get_formatted_cached_speed().as_str()).as_str());
+                    buffer.push_str(format!("{}: {} - {} (was {}) \n", fan.index, fan.label, fan.get_formatted_speed(), fan.current_speed).as_str());
                 }
             }
             buffer.push_str("\nSelect fan that has changed speed, or {enter} if none\n");
 
-            terminal_utils::clear_terminal();
-            print!("{}", buffer);
+            redraw(&buffer);
             buffer.clear();
 
             thread::sleep(Duration::from_millis(100));
@@ -210,5 +192,18 @@ pub fn spawn_live_fan_speed_thread_with_selection(fans: Arc<Vec<Fan>>, header: A
 
 
 pub fn clear_terminal() {
-    std::process::Command::new("clear").status().unwrap();
+    let mut out = io::stdout();
+    let _ = queue!(out, Clear(ClearType::All), Clear(ClearType::Purge), MoveTo(0, 0));
+    let _ = out.flush();
+}
+
+// Overwrites the screen in place instead of clearing it first, so live views don't flicker.
+fn redraw(buffer: &str) {
+    let mut out = io::stdout().lock();
+    let _ = queue!(out, MoveTo(0, 0));
+    for line in buffer.lines() {
+        let _ = queue!(out, Print(line), Clear(ClearType::UntilNewLine), Print("\n"));
+    }
+    let _ = queue!(out, Clear(ClearType::FromCursorDown));
+    let _ = out.flush();
 }
\ No newline at end of file
@@ -0,0 +1,144 @@
+use std::{fmt::{self, Display, Formatter}, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+// A temperature as hwmon reports it, in millidegrees Celsius.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct Temperature(i32);
+
+// A fan speed, hwmon never reports a negative one.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[serde(transparent)]
+pub struct Rpm(u32);
+
+// A pwm duty cycle in the raw 0-255 range pwmN uses.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[serde(transparent)]
+pub struct Duty(u8);
+
+impl Temperature {
+    pub const fn from_millicelsius(millicelsius: i32) -> Self {
+        Self(millicelsius)
+    }
+
+    pub fn from_celsius(celsius: f32) -> Self {
+        Self((celsius * 1000.0).round() as i32)
+    }
+
+    pub const fn millicelsius(self) -> i32 {
+        self.0
+    }
+
+    pub fn celsius(self) -> f32 {
+        self.0 as f32 / 1000.0
+    }
+
+    pub fn fahrenheit(self) -> f32 {
+        self.celsius() * 9.0 / 5.0 + 32.0
+    }
+}
+
+impl Rpm {
+    pub const fn new(rpm: u32) -> Self {
+        Self(rpm)
+    }
+
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+}
+
+impl Duty {
+    pub const OFF: Duty = Duty(0);
+    pub const MAX: Duty = Duty(u8::MAX);
+
+    pub const fn new(raw: u8) -> Self {
+        Self(raw)
+    }
+
+    // Rejects anything outside 0-255 instead of wrapping or clamping it.
+    pub fn from_raw(raw: i32) -> Result<Self, String> {
+        u8::try_from(raw).map(Self).map_err(|_| format!("duty must be between 0 and 255, got {raw}"))
+    }
+
+    pub fn from_percent(percent: f32) -> Result<Self, String> {
+        if !(0.0..=100.0).contains(&percent) {
+            return Err(format!("percentage must be between 0 and 100, got {percent}"));
+        }
+
+        return Ok(Self((percent * 255.0 / 100.0).round() as u8));
+    }
+
+    pub const fn raw(self) -> u8 {
+        self.0
+    }
+
+    pub fn percent(self) -> f32 {
+        f32::from(self.0) * 100.0 / 255.0
+    }
+}
+
+// In °C, or in °F with `{:#}`.
+impl Display for Temperature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            return write!(f, "{} °F", (self.fahrenheit() * 10.0).round() / 10.0);
+        }
+
+        write!(f, "{} °C", self.celsius())
+    }
+}
+
+impl Display for Rpm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} RPM", self.0)
+    }
+}
+
+// The raw value, so a duty prints the way it is written to pwmN and parses back the same.
+impl Display for Duty {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+// Either a raw 0-255 value or a percentage, e.g. "128" or "50%".
+impl FromStr for Duty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(percent) = s.trim().strip_suffix('%') {
+            let percent = percent.trim().parse::<f32>().map_err(|e| format!("invalid percentage '{s}': {e}"))?;
+            return Self::from_percent(percent);
+        }
+
+        let raw = s.trim().parse::<i32>().map_err(|_| format!("duty must be 0-255 or a percentage, got '{s}'"))?;
+        return Self::from_raw(raw);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_and_formats_sensor_values() {
+        let temp = Temperature::from_millicelsius(41500);
+        assert_eq!(temp.celsius(), 41.5);
+        assert_eq!(Temperature::from_celsius(-12.25).millicelsius(), -12250);
+        assert_eq!(temp.to_string(), "41.5 °C");
+        assert_eq!(format!("{temp:#}"), "106.7 °F");
+        assert_eq!(format!("{:#}", Temperature::from_celsius(-40.0)), "-40 °F");
+        assert_eq!(Rpm::new(1200).to_string(), "1200 RPM");
+
+        assert_eq!("128".parse::<Duty>(), Ok(Duty::new(128)));
+        assert_eq!("50%".parse::<Duty>(), Ok(Duty::new(128)));
+        assert_eq!("100 %".parse::<Duty>(), Ok(Duty::MAX));
+        assert!("-1".parse::<Duty>().is_err());
+        assert!("256".parse::<Duty>().is_err());
+        assert!("-5%".parse::<Duty>().is_err());
+        assert!(Duty::from_raw(-40).is_err());
+        assert_eq!(Duty::new(64).to_string().parse::<Duty>(), Ok(Duty::new(64)));
+    }
+}
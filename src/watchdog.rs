@@ -0,0 +1,232 @@
+use std::{collections::{HashMap, HashSet}, fmt::{self, Display, Formatter}, path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use crate::{config::SafetyConfig, error::Error, hwmon::{fans::Fan, temp::Temp}, units::{Rpm, Temperature}};
+
+const MIN_PLAUSIBLE_TEMP: Temperature = Temperature::from_millicelsius(-40_000);
+const MAX_PLAUSIBLE_TEMP: Temperature = Temperature::from_millicelsius(150_000);
+const MAX_PLAUSIBLE_RPM: Rpm = Rpm::new(30_000);
+// A critical temperature has to drop this far below the limit before control resumes
+const CRITICAL_HYSTERESIS: f32 = 5.0;
+
+#[derive(Debug)]
+pub enum Fault {
+    ReadFailed { sensor: String, error: Error },
+    WriteFailed { pwm: String, error: Error },
+    Implausible { sensor: String, value: String },
+    Stale { sensor: String, since: Duration },
+    Critical { sensor: String, temp: Temperature, limit: Temperature },
+    // A curve whose temps weren't found, e.g. the chip is gone or was renamed
+    MissingSource { pwms: String },
+}
+
+// Checks every reading a control loop acts on before it is trusted.
+pub struct Watchdog {
+    critical_temp: Option<f32>,
+    stale_after: Option<Duration>,
+    // Keyed by the tempN_input path, a loop can read sensors with the same name on several chips
+    last_changes: HashMap<PathBuf, (i32, Instant)>,
+    critical: HashSet<PathBuf>,
+}
+
+// Tracks whether fans are forced to full speed so trips and recoveries are only reported once.
+#[derive(Default)]
+pub struct Failsafe {
+    active: Option<String>,
+}
+
+impl Watchdog {
+    pub fn new(config: &SafetyConfig) -> Self {
+        Self {
+            critical_temp: config.critical_temp,
+            stale_after: (config.stale_after_secs > 0).then(|| Duration::from_secs(config.stale_after_secs)),
+            last_changes: HashMap::new(),
+            critical: HashSet::new(),
+        }
+    }
+
+    pub fn check_temp(&mut self, temp: &Temp) -> Result<Temperature, Fault> {
+        let sensor = sensor_name("temp", &temp.index, &temp.label);
+        let reading = temp.get_temp().map_err(|error| Fault::ReadFailed { sensor: sensor.clone(), error })?;
+
+        if !(MIN_PLAUSIBLE_TEMP..=MAX_PLAUSIBLE_TEMP).contains(&reading) {
+            return Err(Fault::Implausible { sensor, value: reading.to_string() });
+        }
+
+        let key = temp.input_path();
+        self.check_stale(&key, &sensor, reading.millicelsius())?;
+
+        let configured = self.critical_temp.map(Temperature::from_celsius);
+        let limit = match (configured, temp.critical_temp()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        if let Some(limit) = limit {
+            let was_critical = self.critical.contains(&key);
+            let threshold = if was_critical { limit.celsius() - CRITICAL_HYSTERESIS } else { limit.celsius() };
+
+            if reading.celsius() >= threshold {
+                self.critical.insert(key);
+                return Err(Fault::Critical { sensor, temp: reading, limit });
+            }
+
+            self.critical.remove(&key);
+        }
+
+        return Ok(reading);
+    }
+
+    pub fn check_fan(&mut self, fan: &Fan) -> Result<Rpm, Fault> {
+        let sensor = sensor_name("fan", &fan.index.to_string(), &fan.label);
+        let rpm = fan.get_speed().map_err(|error| Fault::ReadFailed { sensor: sensor.clone(), error })?;
+
+        if rpm > MAX_PLAUSIBLE_RPM {
+            return Err(Fault::Implausible { sensor, value: rpm.to_string() });
+        }
+
+        return Ok(rpm);
+    }
+
+    fn check_stale(&mut self, key: &Path, sensor: &str, raw: i32) -> Result<(), Fault> {
+        let stale_after = match self.stale_after {
+            Some(d) => d,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let (last, changed) = self.last_changes.entry(key.to_path_buf()).or_insert((raw, now));
+        if *last != raw {
+            *last = raw;
+            *changed = now;
+        }
+
+        let since = now.duration_since(*changed);
+        if since >= stale_after {
+            return Err(Fault::Stale { sensor: sensor.to_string(), since });
+        }
+
+        return Ok(());
+    }
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self::new(&SafetyConfig::default())
+    }
+}
+
+impl Failsafe {
+    // Logs a trip, a change of cause or a recovery. Returns true when the state changed.
+    pub fn update(&mut self, fault: Option<&Fault>) -> bool {
+        let message = fault.map(|f| f.to_string());
+        if message == self.active {
+            return false;
+        }
+
+        match (&message, &self.active) {
+            (Some(m), _) => log::error!("Failsafe: {m}, all managed fans forced to full speed"),
+            (None, Some(_)) => log::info!("Failsafe cleared, resuming curve control"),
+            (None, None) => {}
+        }
+
+        self.active = message;
+        return true;
+    }
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::ReadFailed { sensor, error } => write!(f, "unable to read {sensor}: {error}"),
+            Fault::WriteFailed { pwm, error } => write!(f, "unable to set {pwm}: {error}"),
+            Fault::Implausible { sensor, value } => write!(f, "{sensor} reports an impossible {value}"),
+            Fault::Stale { sensor, since } => write!(f, "{sensor} has not changed for {}s", since.as_secs()),
+            Fault::Critical { sensor, temp, limit } => write!(f, "{sensor} is at {temp}, critical limit is {limit}"),
+            Fault::MissingSource { pwms } => write!(f, "the temps the curve of {pwms} follows no longer exist"),
+        }
+    }
+}
+
+fn sensor_name(kind: &str, index: &str, label: &str) -> String {
+    if label.is_empty() {
+        return format!("{kind}{index}");
+    }
+
+    return format!("{kind}{index} ({label})");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::*;
+    use crate::{control_loop::ControlLoop, curve::Curve, hwmon::{backend::MockBackend, pwm::Pwm}, temp_source::TempSource, units::Duty};
+
+    const CHIP: &str = "/sys/class/hwmon/hwmon0";
+
+    fn setup(backend: MockBackend) -> (Arc<MockBackend>, ControlLoop) {
+        let backend = Arc::new(backend);
+        let temp = Temp::new(backend.clone(), PathBuf::from(CHIP)).with_index("1".into());
+        let pwm = Pwm::new(backend.clone(), PathBuf::from(CHIP)).with_index("1".into()).with_name("pwm1".into());
+        let fan = Fan::new(backend.clone(), PathBuf::from(CHIP)).with_index(1);
+        let curve = Curve::parse("30:50,60:150").unwrap();
+        let config = SafetyConfig { critical_temp: Some(90.0), stale_after_secs: 0 };
+
+        let control_loop = ControlLoop::new(TempSource::single(temp), curve, vec![pwm], Duration::from_secs(1))
+            .with_fans(vec![fan])
+            .with_watchdog(Watchdog::new(&config));
+        return (backend, control_loop);
+    }
+
+    #[test]
+    fn failed_impossible_and_critical_readings_force_full_speed() {
+        let backend = MockBackend::new()
+            .with_chip(CHIP, "nct6775")
+            .with_attribute(CHIP, "temp1_input", "30000")
+            .with_attribute(CHIP, "temp1_crit", "80000")
+            .with_attribute(CHIP, "fan1_input", "900")
+            .with_attribute(CHIP, "pwm1", "128")
+            .with_attribute(CHIP, "pwm1_enable", "1");
+        let (backend, mut control_loop) = setup(backend);
+        let chip = PathBuf::from(CHIP);
+
+        assert_eq!(control_loop.tick().unwrap(), Duty::new(50));
+
+        for (attribute, value) in [("temp1_input", "garbage"), ("temp1_input", "200000"), ("temp1_input", "81000"), ("fan1_input", "-5")] {
+            backend.set(&chip, attribute, value);
+            assert!(control_loop.tick().is_err(), "{attribute}={value} should trip");
+            assert_eq!(backend.get(&chip, "pwm1").as_deref(), Some("255"));
+            backend.set(&chip, "temp1_input", "30000");
+            backend.set(&chip, "fan1_input", "900");
+            backend.set(&chip, "pwm1", "128");
+        }
+
+        // Control resumes only once the critical temperature has dropped below the hysteresis
+        backend.set(&chip, "temp1_input", "81000");
+        assert!(matches!(control_loop.tick(), Err(Fault::Critical { limit, .. }) if limit.celsius() == 80.0));
+        backend.set(&chip, "temp1_input", "77000");
+        assert!(control_loop.tick().is_err());
+        backend.set(&chip, "temp1_input", "74000");
+        assert_eq!(control_loop.tick().unwrap(), Duty::new(150));
+    }
+
+    #[test]
+    fn unchanged_temperature_goes_stale() {
+        let backend = MockBackend::new().with_chip(CHIP, "nct6775").with_attribute(CHIP, "temp1_input", "30000");
+        let (backend, _) = setup(backend);
+        let temp = Temp::new(backend.clone(), PathBuf::from(CHIP)).with_index("1".into());
+        let mut watchdog = Watchdog { stale_after: Some(Duration::from_millis(50)), ..Watchdog::default() };
+
+        assert!(watchdog.check_temp(&temp).is_ok());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(matches!(watchdog.check_temp(&temp), Err(Fault::Stale { .. })));
+
+        backend.set(&PathBuf::from(CHIP), "temp1_input", "30500");
+        assert!(watchdog.check_temp(&temp).is_ok());
+
+        let mut failsafe = Failsafe::default();
+        assert!(failsafe.update(Some(&Fault::Implausible { sensor: "temp1".into(), value: "200 °C".into() })));
+        assert!(!failsafe.update(Some(&Fault::Implausible { sensor: "temp1".into(), value: "200 °C".into() })));
+        assert!(failsafe.update(None));
+    }
+}